@@ -1,408 +1,6808 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{self, Display};
 use std::fs::OpenOptions;
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::ops::{Range, RangeInclusive};
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::str::FromStr;
+use std::time::Instant;
 
 use clap::Parser;
 
 enum Statement {
-    Insert(Row),
-    Select,
+    /// Inserts a row, giving it the id after the largest one if `auto_id`.
+    /// Without `on_conflict`, a duplicate id fails as with `or fail`.
+    Insert {
+        row: Box<Row>,
+        auto_id: bool,
+        on_conflict: Option<ConflictAction>,
+        /// Print the written row, for `returning *`.
+        returning: bool,
+    },
+    Select {
+        projection: Vec<ProjectionExpr>,
+        filter: Option<WhereClause>,
+        group_by: Option<ColumnSelector>,
+        distinct: bool,
+    },
+    Compound {
+        op: CompoundOp,
+        left: Box<Statement>,
+        right: Box<Statement>,
+    },
+    Count(Option<WhereClause>),
+    Aggregate {
+        op: AggregateOp,
+        column: ColumnSelector,
+        filter: Option<WhereClause>,
+    },
+    /// `update rows set <column> = <value>, ... [where ...]`.
+    Update {
+        assignments: Vec<(ColumnSelector, Option<Value>)>,
+        filter: Option<WhereClause>,
+    },
+    /// `delete from` without a where clause, or `truncate table rows`,
+    /// emptying the table.
+    DeleteAll,
+    /// `delete from rows where ...`.
+    Delete(WhereClause),
+    Explain(Box<Statement>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ColumnSelector {
+    Id,
+    Username,
+    Email,
+    Age,
+}
+
+#[derive(Clone, Copy)]
+enum AggregateOp {
+    Count,
+    Min,
+    Max,
+    Sum,
+    Avg,
+}
+
+#[derive(Clone, Copy)]
+enum FnName {
+    Upper,
+    Lower,
+    Length,
+    Substr { start: i64, length: i64 },
+}
+
+enum ProjectionExpr {
+    Column(ColumnSelector),
+    Literal(Option<String>),
+    Function(FnName, ColumnSelector),
+    Coalesce(Vec<ProjectionExpr>),
+    CountAll,
+    Aggregate(AggregateOp, ColumnSelector),
+}
+
+enum WhereClause {
+    Like {
+        column: ColumnSelector,
+        pattern: String,
+    },
+    Compare {
+        column: ColumnSelector,
+        op: CompareOp,
+        value: Value,
+    },
+    /// `column between low and high`, inclusive at both ends.
+    Between {
+        column: ColumnSelector,
+        low: Value,
+        high: Value,
+    },
+    /// `column in (select ...)`, before the subquery has been run.
+    InSubquery {
+        column: ColumnSelector,
+        query: Box<Statement>,
+    },
+    /// `column in (...)` against a known set of rendered values.
+    In {
+        column: ColumnSelector,
+        values: HashSet<String>,
+        has_null: bool,
+    },
+    /// `column is null`, which is never unknown.
+    IsNull(ColumnSelector),
+    /// `column is not null`.
+    IsNotNull(ColumnSelector),
+    Not(Box<WhereClause>),
+    And(Box<WhereClause>, Box<WhereClause>),
+    Or(Box<WhereClause>, Box<WhereClause>),
+}
+
+/// What `insert or <action>` or `insert ... on conflict do <action>` does
+/// when a row with the same id exists.
+enum ConflictAction {
+    Fail,
+    Ignore,
+    Replace,
+    /// Sets columns of the existing row instead.
+    Update {
+        assignments: Vec<(ColumnSelector, Option<Value>)>,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum CompoundOp {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+#[derive(Clone, Copy)]
+enum CompareOp {
+    Eq,
+    Gt,
+    Lt,
+    Ge,
+    Le,
 }
 
 enum PrepareResult {
     SyntaxError,
     StringTooLong,
+    DivisionByZero,
+    TypeMismatch,
     UnrecognizedStatement,
 }
 
+enum ExecuteResult {
+    RowsAffected(usize),
+    RowsReturned,
+}
+
+#[derive(Debug)]
+enum MysqliteError {
+    Overflow,
+    /// Writing back a page, or syncing the file when `page` is `None`, failed.
+    FlushFailed {
+        page: Option<usize>,
+        source: io::Error,
+    },
+    /// Reading page `page` from the file failed.
+    ReadFailed {
+        page: usize,
+        source: io::Error,
+    },
+    /// Another handle holds the database's exclusive lock.
+    DatabaseLocked,
+    /// The table was opened read-only and can't be modified.
+    ReadOnly,
+    /// `insert or fail` found a row that already has this id.
+    DuplicateId(u32),
+    /// The table already holds as many rows as it was opened to allow.
+    TableFull(usize),
+}
+
 enum MetaCommandResult {
     UnrecognizedCommand,
 }
 
+#[derive(Clone, Copy, Default)]
+enum OutputMode {
+    #[default]
+    Tuple,
+    Line,
+    /// Space-padded columns under a header, which needs the whole result
+    /// before the first row can be printed.
+    Column,
+}
+
+/// How thoroughly closing a table syncs the file in place.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum SyncMode {
+    /// Sync the contents and length, but not metadata such as the mtime.
+    #[default]
+    Data,
+    /// Sync the contents along with all metadata.
+    Full,
+    /// Leave syncing to the operating system.
+    None,
+}
+
 enum RunControl {
     Exit,
+    Version,
+    Generate(usize),
+    Echo(bool),
+    Mode(OutputMode),
+    /// Redirect statement results to a file, or back to stdout on `None`.
+    Output(Option<PathBuf>),
+    PageInfo(usize),
+    PageCount,
+    Stats,
+    /// Show the stored bytes of the row with this id.
+    Get(u32),
+    Clear,
+    Check,
+    Sync(SyncMode),
+    /// Run the commands in a script file as if they were typed.
+    Read(PathBuf),
 }
 
+/// Settings that REPL commands change, shared with scripts run by `.read`.
+#[derive(Default)]
+struct Session {
+    echo: bool,
+    mode: OutputMode,
+    redirect: Option<std::fs::File>,
+    /// How many `.read` scripts are running inside one another.
+    depth: usize,
+}
+
+impl Session {
+    /// Stops a script that reads itself from recursing forever.
+    const MAX_READ_DEPTH: usize = 16;
+}
+
+#[derive(Clone)]
+enum Value {
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 struct Row {
+    nulls: u8,
     id: u32,
-    username: [u8; Self::USERNAME_SIZE],
-    email: [u8; Self::EMAIL_SIZE],
+    username: Vec<u8>,
+    email: Vec<u8>,
+    age: u16,
+}
+
+/// The byte sizes of the pages and the text columns. Every row is stored
+/// in the same number of bytes, so these fix the layout of the whole file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Schema {
+    page_size: usize,
+    username_size: usize,
+    email_size: usize,
 }
 
 impl Row {
+    const NULLS_SIZE: usize = std::mem::size_of::<u8>();
     const ID_SIZE: usize = std::mem::size_of::<u32>();
-    const USERNAME_SIZE: usize = 32;
-    const EMAIL_SIZE: usize = 255;
-    const SIZE: usize = Self::ID_SIZE + Self::USERNAME_SIZE + Self::EMAIL_SIZE;
+    const AGE_SIZE: usize = std::mem::size_of::<u16>();
 
-    fn username_str(&self) -> &str {
-        Self::bytes_to_str(&self.username)
+    const NULLS_OFFSET: usize = 0;
+    const ID_OFFSET: usize = Self::NULLS_OFFSET + Self::NULLS_SIZE;
+
+    /// How many bytes version 1 files store each row in: the id, then the
+    /// username and email padded to the default sizes, with neither a null
+    /// bitmap nor an age.
+    const VERSION_1_SIZE: usize =
+        Self::ID_SIZE + Schema::DEFAULT_USERNAME_SIZE + Schema::DEFAULT_EMAIL_SIZE;
+
+    /// Builds a row with no NULLs, checking that its text fits `schema`.
+    fn new(
+        schema: &Schema,
+        id: u32,
+        username: &str,
+        email: &str,
+        age: u16,
+    ) -> Result<Self, PrepareResult> {
+        let row = Self::unchecked(id, username, email, age);
+        schema.check(&row)?;
+        Ok(row)
     }
 
-    fn email_str(&self) -> &str {
-        Self::bytes_to_str(&self.email)
+    /// Builds a row with no NULLs, for parsers that don't know the table's
+    /// schema yet. The statement checks the row once it does.
+    fn unchecked(id: u32, username: &str, email: &str, age: u16) -> Self {
+        Self {
+            nulls: 0,
+            id,
+            username: username.as_bytes().to_vec(),
+            email: email.as_bytes().to_vec(),
+            age,
+        }
     }
 
-    fn bytes_to_str(bytes: &[u8]) -> &str {
-        bytes
-            .split(|&b| b == 0)
-            .next()
-            .and_then(|s| std::str::from_utf8(s).ok())
-            .unwrap_or("<Invalid utf-8>")
+    fn is_null(&self, column: ColumnSelector) -> bool {
+        self.nulls & column.null_bit() != 0
     }
-}
 
-impl FromStr for Row {
-    type Err = PrepareResult;
+    /// Writes the row into `buf`, which must be `schema.row_size()` bytes
+    /// long, padding the text with NULs. The text must fit `schema`.
+    fn serialize(&self, schema: &Schema, buf: &mut [u8]) {
+        buf[Self::NULLS_OFFSET] = self.nulls;
+        for (column, bytes) in [
+            (ColumnSelector::Id, &self.id.to_le_bytes()[..]),
+            (ColumnSelector::Username, &self.username[..]),
+            (ColumnSelector::Email, &self.email[..]),
+            (ColumnSelector::Age, &self.age.to_le_bytes()[..]),
+        ] {
+            let (offset, size) = schema.field(column);
+            let field = &mut buf[offset..offset + size];
+            field.fill(0);
+            field[..bytes.len()].copy_from_slice(bytes);
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut parts = s.split_whitespace();
-        let id = parts
-            .next()
-            .ok_or(PrepareResult::SyntaxError)?
-            .parse()
-            .map_err(|_| PrepareResult::SyntaxError)?;
+    /// Reads a row back from the bytes `serialize` wrote with `schema`.
+    fn deserialize(schema: &Schema, buf: &[u8]) -> Self {
+        let field = |column| {
+            let (offset, size) = schema.field(column);
+            &buf[offset..offset + size]
+        };
+        // Text ends at the first NUL of its padding.
+        let text = |column| {
+            let bytes: &[u8] = field(column);
+            bytes.split(|&b| b == 0).next().unwrap_or_default().to_vec()
+        };
+        let mut id = [0; Self::ID_SIZE];
+        let mut age = [0; Self::AGE_SIZE];
+        id.copy_from_slice(field(ColumnSelector::Id));
+        age.copy_from_slice(field(ColumnSelector::Age));
 
-        let username = parts.next().ok_or(PrepareResult::SyntaxError)?.as_bytes();
-        if username.len() > Self::USERNAME_SIZE {
-            return Err(PrepareResult::StringTooLong);
+        Self {
+            nulls: buf[Self::NULLS_OFFSET],
+            id: u32::from_le_bytes(id),
+            username: text(ColumnSelector::Username),
+            email: text(ColumnSelector::Email),
+            age: u16::from_le_bytes(age),
         }
+    }
 
-        let email = parts.next().ok_or(PrepareResult::SyntaxError)?.as_bytes();
-        if email.len() > Self::EMAIL_SIZE {
-            return Err(PrepareResult::StringTooLong);
+    /// Reads a row laid out as version 1 files store it, giving it an age
+    /// of 0.
+    fn deserialize_version_1(buf: &[u8]) -> Self {
+        let text = |bytes: &[u8]| bytes.split(|&b| b == 0).next().unwrap_or_default().to_vec();
+        let (id, text_fields) = buf.split_at(Self::ID_SIZE);
+        let (username, email) = text_fields.split_at(Schema::DEFAULT_USERNAME_SIZE);
+
+        Self {
+            nulls: 0,
+            id: u32::from_le_bytes(id.try_into().expect("id field has ID_SIZE bytes")),
+            username: text(username),
+            email: text(email),
+            age: 0,
         }
+    }
 
-        let mut row = Self {
-            id,
-            username: [0; Self::USERNAME_SIZE],
-            email: [0; Self::EMAIL_SIZE],
+    /// The id the table is ordered by, where `None` is a NULL id.
+    fn key(&self) -> Option<u32> {
+        (!self.is_null(ColumnSelector::Id)).then_some(self.id)
+    }
+
+    fn set_null(&mut self, column: ColumnSelector) {
+        self.nulls |= column.null_bit();
+    }
+
+    /// Returns the value of `column`, or `None` if it is null.
+    fn value(&self, column: ColumnSelector) -> Option<Value> {
+        if self.is_null(column) {
+            return None;
+        }
+
+        let value = match column {
+            ColumnSelector::Id => Value::Integer(self.id.into()),
+            ColumnSelector::Username => Value::Text(self.username_str().to_string()),
+            ColumnSelector::Email => Value::Text(self.email_str().to_string()),
+            ColumnSelector::Age => Value::Integer(self.age.into()),
         };
+        Some(value)
+    }
 
-        row.username[..username.len()].copy_from_slice(username);
-        row.email[..email.len()].copy_from_slice(email);
+    fn username_str(&self) -> &str {
+        Self::bytes_to_str(&self.username)
+    }
 
-        Ok(row)
+    fn email_str(&self) -> &str {
+        Self::bytes_to_str(&self.email)
     }
-}
 
-impl Display for Row {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "({} {} {})",
-            self.id,
-            self.username_str(),
-            self.email_str()
-        )
+    fn bytes_to_str(bytes: &[u8]) -> &str {
+        Self::try_bytes_to_str(bytes).unwrap_or("<Invalid utf-8>")
+    }
+
+    /// Returns the text before the first NUL, or `None` if it isn't UTF-8.
+    fn try_bytes_to_str(bytes: &[u8]) -> Option<&str> {
+        bytes
+            .split(|&b| b == 0)
+            .next()
+            .and_then(|s| std::str::from_utf8(s).ok())
     }
 }
 
-struct Pager {
-    file: std::fs::File,
-    pages: Vec<Option<Box<[u8; Pager::SIZE]>>>,
+impl Default for Schema {
+    fn default() -> Self {
+        Self {
+            page_size: Self::DEFAULT_PAGE_SIZE,
+            username_size: Self::DEFAULT_USERNAME_SIZE,
+            email_size: Self::DEFAULT_EMAIL_SIZE,
+        }
+    }
 }
 
-impl Pager {
-    const SIZE: usize = 4096;
+impl Schema {
+    const DEFAULT_PAGE_SIZE: usize = 4096;
+    const DEFAULT_USERNAME_SIZE: usize = 32;
+    const DEFAULT_EMAIL_SIZE: usize = 255;
+    const PAGE_SIZES: RangeInclusive<usize> = 512..=65536;
 
-    fn new(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .mode(0o0600)
-            .open(path)?;
+    /// Starts the header page of every file, and is followed by the version
+    /// and the two text column sizes as little-endian `u16`s, then the page
+    /// size as a little-endian `u32`. Version 1 files, from before the null
+    /// bitmap and the age column, have no header and start straight with a
+    /// row.
+    const MAGIC: &[u8] = b"mysqlite";
+    const VERSION: u16 = 2;
+    const HEADER_SIZE: usize =
+        Self::MAGIC.len() + 3 * std::mem::size_of::<u16>() + std::mem::size_of::<u32>();
 
-        let file_length = file.metadata()?.len();
-        let page_count = usize::try_from(file_length.div_ceil(Self::SIZE as u64))?;
+    /// Returns how many bytes each row is stored in.
+    fn row_size(&self) -> usize {
+        Row::NULLS_SIZE + Row::ID_SIZE + self.username_size + self.email_size + Row::AGE_SIZE
+    }
 
-        Ok(Self {
-            file,
-            pages: vec![None; page_count],
-        })
+    fn rows_per_page(&self) -> usize {
+        self.page_size / self.row_size()
     }
 
-    fn get_page(&mut self, page_num: usize) -> Result<&mut [u8; Self::SIZE], Box<dyn Error>> {
-        if page_num >= self.pages.len() {
-            self.pages.resize(page_num + 1, None);
+    /// Returns the offset within a serialized row and the size of `column`.
+    fn field(&self, column: ColumnSelector) -> (usize, usize) {
+        let username_offset = Row::ID_OFFSET + Row::ID_SIZE;
+        let email_offset = username_offset + self.username_size;
+        match column {
+            ColumnSelector::Id => (Row::ID_OFFSET, Row::ID_SIZE),
+            ColumnSelector::Username => (username_offset, self.username_size),
+            ColumnSelector::Email => (email_offset, self.email_size),
+            ColumnSelector::Age => (email_offset + self.email_size, Row::AGE_SIZE),
         }
+    }
 
-        if self.pages[page_num].is_none() {
-            let mut page = Box::new([0u8; Self::SIZE]);
-
-            let file_length = self.file.metadata()?.len();
-            let num_pages = file_length.div_ceil(Self::SIZE as u64);
+    /// Returns the size of a text column, or `None` for columns that don't
+    /// hold text.
+    fn text_size(&self, column: ColumnSelector) -> Option<usize> {
+        column.is_text().then(|| self.field(column).1)
+    }
 
-            if (page_num as u64) < num_pages {
-                let offset = page_num as u64 * Self::SIZE as u64;
-                let bytes_to_read = usize::try_from(std::cmp::min(
-                    Self::SIZE as u64,
-                    file_length.saturating_sub(offset),
-                ))?;
+    /// Checks that the text in `row` fits its columns.
+    fn check(&self, row: &Row) -> Result<(), PrepareResult> {
+        if row.username.len() > self.username_size || row.email.len() > self.email_size {
+            return Err(PrepareResult::StringTooLong);
+        }
+        Ok(())
+    }
 
-                self.file.read_exact(&mut page[..bytes_to_read])?;
-            }
+    /// Checks that the page size is a power of two in `PAGE_SIZES`, that
+    /// the text columns hold at least a byte each and that a row fits in a
+    /// page, which also keeps the column sizes within a `u16`.
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.page_size.is_power_of_two() || !Self::PAGE_SIZES.contains(&self.page_size) {
+            return Err(format!(
+                "the page size must be a power of two from {} to {} bytes",
+                Self::PAGE_SIZES.start(),
+                Self::PAGE_SIZES.end()
+            )
+            .into());
+        }
+        if self.username_size == 0 || self.email_size == 0 || self.row_size() > self.page_size {
+            return Err(format!(
+                "field sizes must be at least 1 byte, and a row must fit in a {}-byte page",
+                self.page_size
+            )
+            .into());
+        }
+        Ok(())
+    }
 
-            self.pages[page_num] = Some(page);
+    /// Returns the header page recording this schema.
+    fn header(&self) -> Vec<u8> {
+        let mut page = vec![0; self.page_size];
+        let sizes = [
+            Self::VERSION,
+            self.username_size as u16,
+            self.email_size as u16,
+        ];
+        page[..Self::MAGIC.len()].copy_from_slice(Self::MAGIC);
+        for (i, value) in sizes.into_iter().enumerate() {
+            let at = Self::MAGIC.len() + 2 * i;
+            page[at..at + 2].copy_from_slice(&value.to_le_bytes());
         }
+        let at = Self::HEADER_SIZE - 4;
+        page[at..Self::HEADER_SIZE].copy_from_slice(&(self.page_size as u32).to_le_bytes());
+        page
+    }
 
-        let page = self.pages[page_num]
-            .as_deref_mut()
-            .expect("page must be initialized before returning");
+    /// Reads the schema from the header at the start of `file`, or returns
+    /// `None` if the file has no header.
+    fn read_header(file: &std::fs::File) -> Result<Option<Self>, Box<dyn Error>> {
+        let mut file = file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = vec![];
+        file.take(Self::HEADER_SIZE as u64)
+            .read_to_end(&mut header)?;
+        if !header.starts_with(Self::MAGIC) {
+            return Ok(None);
+        }
+        if header.len() < Self::HEADER_SIZE {
+            return Err("corrupt database (partial header)".into());
+        }
 
-        Ok(page)
+        let value = |i: usize| {
+            let at = Self::MAGIC.len() + 2 * i;
+            u16::from_le_bytes([header[at], header[at + 1]])
+        };
+        if value(0) != Self::VERSION {
+            return Err(format!("unsupported database version {}", value(0)).into());
+        }
+        let at = Self::HEADER_SIZE - 4;
+        let page_size =
+            u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+        let schema = Self {
+            page_size: usize::try_from(page_size)?,
+            username_size: value(1).into(),
+            email_size: value(2).into(),
+        };
+        schema
+            .validate()
+            .map_err(|_| "corrupt database (bad page or field sizes)")?;
+        Ok(Some(schema))
     }
+}
 
-    fn flush_page(&mut self, index: usize, size: usize) -> io::Result<()> {
-        let Some(page) = &self.pages[index] else {
-            return Ok(());
-        };
+impl FromStr for Row {
+    type Err = PrepareResult;
 
-        let offset = (index as u64) * (Self::SIZE as u64);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_start();
+        let (id, rest) = if s.starts_with('(') {
+            let mut tokens = Tokens::new(s);
+            let id = eval_expr(&mut tokens)?;
+            let id = u32::try_from(id).map_err(|_| PrepareResult::SyntaxError)?;
+            (Some(id), tokens.rest())
+        } else {
+            let (id, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+            let id = nullable(id)
+                .map(str::parse)
+                .transpose()
+                .map_err(|_| PrepareResult::SyntaxError)?;
+            (id, rest)
+        };
 
-        self.file.seek(SeekFrom::Start(offset))?;
-        self.file.write_all(&page[..size])
+        Self::from_fields(id, rest)
     }
 }
 
-struct Table {
-    row_count: usize,
-    pager: Pager,
-}
+impl Row {
+    /// Parses the columns after the id, `rest` being the username, email
+    /// and optional age. The age may be an arithmetic expression.
+    fn from_fields(id: Option<u32>, rest: &str) -> Result<Self, PrepareResult> {
+        let words: Vec<_> = rest.split_whitespace().collect();
+        let [username, email, age @ ..] = words.as_slice() else {
+            return Err(PrepareResult::SyntaxError);
+        };
+        let age = age.join(" ");
+        let age = match nullable(&age) {
+            Some("") | None => None,
+            age => parse_number(age).map_err(|err| match err {
+                PrepareResult::DivisionByZero => err,
+                _ => PrepareResult::SyntaxError,
+            })?,
+        };
 
-impl Table {
-    const ROWS_PER_PAGE: usize = Pager::SIZE / Row::SIZE;
+        Ok(Self::from_values(
+            id,
+            nullable(username).map(unquote_empty),
+            nullable(email).map(unquote_empty),
+            age,
+        ))
+    }
 
-    fn new(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
-        let pager = Pager::new(path)?;
-        let file_length = usize::try_from(pager.file.metadata()?.len())?;
-        let row_count = file_length / Row::SIZE;
+    /// Builds a row from column values, where `None` is NULL. Like
+    /// `Row::unchecked`, it leaves `Schema::check` to the statement.
+    fn from_values(
+        id: Option<u32>,
+        username: Option<&str>,
+        email: Option<&str>,
+        age: Option<u16>,
+    ) -> Self {
+        let mut row = Self::unchecked(
+            id.unwrap_or(0),
+            username.unwrap_or(""),
+            email.unwrap_or(""),
+            age.unwrap_or(0),
+        );
+        if id.is_none() {
+            row.set_null(ColumnSelector::Id);
+        }
+        if username.is_none() {
+            row.set_null(ColumnSelector::Username);
+        }
+        if email.is_none() {
+            row.set_null(ColumnSelector::Email);
+        }
+        if age.is_none() {
+            row.set_null(ColumnSelector::Age);
+        }
 
-        Ok(Self { row_count, pager })
+        row
     }
+}
 
-    fn close(&mut self) -> io::Result<()> {
-        let full_page_count = self.row_count / Self::ROWS_PER_PAGE;
-        for i in 0..full_page_count {
-            if self.pager.pages[i].is_some() {
-                self.pager.flush_page(i, Pager::SIZE)?;
-            }
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer(value) => write!(f, "{value}"),
+            Self::Real(value) => write!(f, "{value:?}"),
+            Self::Text(value) => write!(f, "{value}"),
         }
+    }
+}
 
-        let additional_row_count = self.row_count % Self::ROWS_PER_PAGE;
-        if additional_row_count > 0 {
-            self.pager
-                .flush_page(full_page_count, additional_row_count * Row::SIZE)?;
-        }
+/// Numbers order before text, and reals use IEEE 754 total ordering so that
+/// NaN compares consistently.
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
 
-        self.pager.file.sync_all()
+        match (self, other) {
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            (Self::Integer(a), Self::Real(b)) => (*a as f64).total_cmp(b),
+            (Self::Real(a), Self::Integer(b)) => a.total_cmp(&(*b as f64)),
+            (Self::Real(a), Self::Real(b)) => a.total_cmp(b),
+            (Self::Text(a), Self::Text(b)) => a.cmp(b),
+            (Self::Text(_), _) => Ordering::Greater,
+            (_, Self::Text(_)) => Ordering::Less,
+        }
     }
+}
 
-    fn insert(&mut self, row: &Row) -> Result<(), Box<dyn Error>> {
-        let page_num = self.row_count / Self::ROWS_PER_PAGE;
-
-        let row_offset = self.row_count % Self::ROWS_PER_PAGE;
-        let byte_offset = row_offset * Row::SIZE;
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        let page = self.pager.get_page(page_num)?;
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
 
-        page[byte_offset..byte_offset + Row::ID_SIZE].copy_from_slice(&row.id.to_le_bytes());
+impl Eq for Value {}
 
-        let username_offset = byte_offset + Row::ID_SIZE;
-        page[username_offset..username_offset + Row::USERNAME_SIZE].copy_from_slice(&row.username);
+impl Display for MysqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overflow => write!(f, "integer overflow"),
+            Self::FlushFailed {
+                page: Some(page),
+                source,
+            } => write!(f, "failed to persist page {page}: {source}"),
+            Self::FlushFailed { page: None, source } => {
+                write!(f, "failed to persist database: {source}")
+            }
+            Self::ReadFailed { page, source } => write!(f, "failed to read page {page}: {source}"),
+            Self::DatabaseLocked => write!(f, "database is locked"),
+            Self::ReadOnly => write!(f, "database is read-only"),
+            Self::DuplicateId(id) => write!(f, "duplicate id {id}"),
+            Self::TableFull(max_rows) => write!(f, "table is full ({max_rows} rows)"),
+        }
+    }
+}
 
-        let email_offset = username_offset + Row::USERNAME_SIZE;
-        page[email_offset..email_offset + Row::EMAIL_SIZE].copy_from_slice(&row.email);
+impl Error for MysqliteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Overflow
+            | Self::DatabaseLocked
+            | Self::ReadOnly
+            | Self::DuplicateId(_)
+            | Self::TableFull(_) => None,
+            Self::FlushFailed { source, .. } | Self::ReadFailed { source, .. } => Some(source),
+        }
+    }
+}
 
-        self.row_count += 1;
+impl MysqliteError {
+    fn flush_failed(page: Option<usize>) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::FlushFailed { page, source }
+    }
 
-        Ok(())
+    fn read_failed(page: usize) -> impl FnOnce(io::Error) -> Self {
+        move |source| Self::ReadFailed { page, source }
     }
+}
 
-    fn select<W>(&mut self, output: &mut W) -> Result<(), Box<dyn Error>>
-    where
-        W: io::Write,
-    {
-        for i in 0..self.row_count {
-            let row = self.deserialize_row(i)?;
-            writeln!(output, "{row}")?;
+impl FromStr for ColumnSelector {
+    type Err = PrepareResult;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(Self::Id),
+            "username" => Ok(Self::Username),
+            "email" => Ok(Self::Email),
+            "age" => Ok(Self::Age),
+            _ => Err(PrepareResult::SyntaxError),
         }
+    }
+}
 
-        Ok(())
+impl Display for ColumnSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Id => "id",
+            Self::Username => "username",
+            Self::Email => "email",
+            Self::Age => "age",
+        };
+        write!(f, "{name}")
     }
+}
 
-    fn deserialize_row(&mut self, index: usize) -> Result<Row, Box<dyn Error>> {
-        let page_num = index / Self::ROWS_PER_PAGE;
-        let row_offset = index % Self::ROWS_PER_PAGE;
-        let byte_offset = row_offset * Row::SIZE;
+impl ColumnSelector {
+    const ALL: [Self; 4] = [Self::Id, Self::Username, Self::Email, Self::Age];
 
-        let page = self.pager.get_page(page_num)?;
-        let id = u32::from_le_bytes(page[byte_offset..byte_offset + Row::ID_SIZE].try_into()?);
+    /// Returns the bit flagging this column as null in a row's bitmap.
+    fn null_bit(self) -> u8 {
+        match self {
+            Self::Id => 1 << 0,
+            Self::Username => 1 << 1,
+            Self::Email => 1 << 2,
+            Self::Age => 1 << 3,
+        }
+    }
 
-        let mut username = [0; Row::USERNAME_SIZE];
-        let mut email = [0; Row::EMAIL_SIZE];
+    fn is_text(self) -> bool {
+        matches!(self, Self::Username | Self::Email)
+    }
+}
 
-        let username_offset = byte_offset + Row::ID_SIZE;
-        username.copy_from_slice(&page[username_offset..username_offset + Row::USERNAME_SIZE]);
+impl FromStr for CompareOp {
+    type Err = PrepareResult;
 
-        let email_offset = username_offset + Row::USERNAME_SIZE;
-        email.copy_from_slice(&page[email_offset..email_offset + Row::EMAIL_SIZE]);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "=" => Ok(Self::Eq),
+            ">" => Ok(Self::Gt),
+            "<" => Ok(Self::Lt),
+            ">=" => Ok(Self::Ge),
+            "<=" => Ok(Self::Le),
+            _ => Err(PrepareResult::SyntaxError),
+        }
+    }
+}
 
-        Ok(Row {
-            id,
-            username,
-            email,
-        })
+impl Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Eq => write!(f, "="),
+            Self::Gt => write!(f, ">"),
+            Self::Lt => write!(f, "<"),
+            Self::Ge => write!(f, ">="),
+            Self::Le => write!(f, "<="),
+        }
     }
 }
 
-fn prepare_statement(input_buffer: &str) -> Result<Statement, PrepareResult> {
-    if let Some(stripped) = input_buffer.strip_prefix("insert") {
-        let row = Row::from_str(stripped)?;
-        Ok(Statement::Insert(row))
-    } else if input_buffer.starts_with("select") {
-        Ok(Statement::Select)
-    } else {
-        Err(PrepareResult::UnrecognizedStatement)
+impl Display for CompoundOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Union => write!(f, "union"),
+            Self::UnionAll => write!(f, "union all"),
+            Self::Intersect => write!(f, "intersect"),
+            Self::Except => write!(f, "except"),
+        }
     }
 }
 
-fn execute_statement<W>(
-    statement: &Statement,
-    table: &mut Table,
-    output: &mut W,
-) -> Result<(), Box<dyn Error>>
-where
-    W: io::Write,
-{
-    match statement {
-        Statement::Insert(row) => {
-            table.insert(row)?;
-            Ok(())
+impl CompareOp {
+    /// Whether a field that orders as `ordering` against the literal passes.
+    fn holds(self, ordering: std::cmp::Ordering) -> bool {
+        match self {
+            Self::Eq => ordering.is_eq(),
+            Self::Gt => ordering.is_gt(),
+            Self::Lt => ordering.is_lt(),
+            Self::Ge => ordering.is_ge(),
+            Self::Le => ordering.is_le(),
         }
-        Statement::Select => table.select(output),
     }
 }
 
-fn print_prompt<W>(output: &mut W) -> io::Result<()>
-where
-    W: io::Write,
-{
-    write!(output, "mysqlite> ")?;
-    output.flush()
+impl FromStr for AggregateOp {
+    type Err = PrepareResult;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "count" => Ok(Self::Count),
+            "min" => Ok(Self::Min),
+            "max" => Ok(Self::Max),
+            "sum" => Ok(Self::Sum),
+            "avg" => Ok(Self::Avg),
+            _ => Err(PrepareResult::SyntaxError),
+        }
+    }
 }
 
-fn read_input<'a, R>(input: &mut R, input_buffer: &'a mut String) -> Result<&'a str, io::Error>
-where
-    R: io::BufRead,
-{
-    input_buffer.clear();
-    input.read_line(input_buffer)?;
-    Ok(input_buffer.trim())
+impl Display for AggregateOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Count => write!(f, "count"),
+            Self::Min => write!(f, "min"),
+            Self::Max => write!(f, "max"),
+            Self::Sum => write!(f, "sum"),
+            Self::Avg => write!(f, "avg"),
+        }
+    }
 }
 
-fn do_meta_command(command: &str) -> Result<RunControl, MetaCommandResult> {
-    match command {
-        ".exit" => Ok(RunControl::Exit),
-        _ => Err(MetaCommandResult::UnrecognizedCommand),
+impl FnName {
+    /// Parses a function name together with the arguments that follow its
+    /// column argument.
+    fn parse(name: &str, args: &[&str]) -> Result<Self, PrepareResult> {
+        let integer = |arg: &str| arg.parse().map_err(|_| PrepareResult::SyntaxError);
+
+        match (name.to_ascii_lowercase().as_str(), args) {
+            ("upper", []) => Ok(Self::Upper),
+            ("lower", []) => Ok(Self::Lower),
+            ("length", []) => Ok(Self::Length),
+            ("substr", [start, length]) => Ok(Self::Substr {
+                start: integer(start)?,
+                length: integer(length)?,
+            }),
+            _ => Err(PrepareResult::SyntaxError),
+        }
     }
 }
 
-fn run<R, W>(input: &mut R, output: &mut W, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>>
-where
-    R: io::BufRead,
-    W: io::Write,
-{
-    let mut table = Table::new(path)?;
-    let mut input_buffer = String::new();
+impl FromStr for ProjectionExpr {
+    type Err = PrepareResult;
 
-    loop {
-        print_prompt(output)?;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let Some((name, args)) = s.split_once('(') else {
+            // A bare `count` is shorthand for `count(*)`.
+            if s.eq_ignore_ascii_case("count") {
+                return Ok(Self::CountAll);
+            }
+            return Ok(Self::Column(s.parse()?));
+        };
 
-        let command = read_input(input, &mut input_buffer)?;
+        let args: Vec<_> = args
+            .strip_suffix(')')
+            .ok_or(PrepareResult::SyntaxError)?
+            .split(',')
+            .map(str::trim)
+            .collect();
 
-        if command.is_empty() {
-            continue;
+        if let Ok(op) = name.trim().parse() {
+            return match (op, args.as_slice()) {
+                (AggregateOp::Count, ["*"]) => Ok(Self::CountAll),
+                (AggregateOp::Sum | AggregateOp::Avg, [column]) => {
+                    let column: ColumnSelector = column.parse()?;
+                    if column.is_text() {
+                        return Err(PrepareResult::TypeMismatch);
+                    }
+                    Ok(Self::Aggregate(op, column))
+                }
+                (_, [column]) => Ok(Self::Aggregate(op, column.parse()?)),
+                _ => Err(PrepareResult::SyntaxError),
+            };
         }
 
-        if command.starts_with('.') {
-            match do_meta_command(command) {
-                Ok(RunControl::Exit) => {
-                    table.close()?;
-                    return Ok(());
-                }
-                Err(MetaCommandResult::UnrecognizedCommand) => {
-                    writeln!(output, "Unrecognized command '{command}'")?;
-                }
-            }
-            continue;
+        if name.trim().eq_ignore_ascii_case("coalesce") {
+            let args = args
+                .into_iter()
+                .map(Self::parse_operand)
+                .collect::<Result<_, _>>()?;
+            return Ok(Self::Coalesce(args));
         }
 
-        let statement = match prepare_statement(command) {
-            Ok(statement) => statement,
+        let column: ColumnSelector = args[0].parse()?;
+        let function = FnName::parse(name.trim(), &args[1..])?;
+
+        if matches!(function, FnName::Length) && !column.is_text() {
+            return Err(PrepareResult::TypeMismatch);
+        }
+
+        Ok(Self::Function(function, column))
+    }
+}
+
+impl Display for ProjectionExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Column(column) => write!(f, "{column}"),
+            Self::Literal(Some(value)) => write!(f, "'{value}'"),
+            Self::Literal(None) => write!(f, "NULL"),
+            Self::Function(FnName::Upper, column) => write!(f, "upper({column})"),
+            Self::Function(FnName::Lower, column) => write!(f, "lower({column})"),
+            Self::Function(FnName::Length, column) => write!(f, "length({column})"),
+            Self::Function(FnName::Substr { start, length }, column) => {
+                write!(f, "substr({column}, {start}, {length})")
+            }
+            Self::Coalesce(args) => {
+                let args: Vec<_> = args.iter().map(ToString::to_string).collect();
+                write!(f, "coalesce({})", args.join(", "))
+            }
+            Self::CountAll => write!(f, "count(*)"),
+            Self::Aggregate(op, column) => write!(f, "{op}({column})"),
+        }
+    }
+}
+
+impl OutputMode {
+    /// Writes one result row: a `(v1 v2 ...)` tuple, or one `name = value`
+    /// line per column with a blank line between rows.
+    fn write_row<W>(
+        self,
+        output: &mut W,
+        names: &[String],
+        values: &[Option<String>],
+        first: bool,
+    ) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        let values = values
+            .iter()
+            .map(|value| value.as_deref().unwrap_or("NULL"));
+
+        match self {
+            Self::Tuple => {
+                let values: Vec<_> = values.map(quote_empty).collect();
+                writeln!(output, "({})", values.join(" "))
+            }
+            Self::Column => unreachable!("column output is written by ResultSet::write"),
+            Self::Line => {
+                if !first {
+                    writeln!(output)?;
+                }
+                for (name, value) in names.iter().zip(values) {
+                    writeln!(output, "{name} = {value}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ProjectionExpr {
+    /// Parses a function argument, which is either a column or a literal:
+    /// `null`, an integer, or a single-quoted string.
+    fn parse_operand(s: &str) -> Result<Self, PrepareResult> {
+        if let Ok(column) = s.parse() {
+            Ok(Self::Column(column))
+        } else if s.eq_ignore_ascii_case("null") {
+            Ok(Self::Literal(None))
+        } else if let Some(text) = s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+            Ok(Self::Literal(Some(text.to_string())))
+        } else if s.parse::<i64>().is_ok() {
+            Ok(Self::Literal(Some(s.to_string())))
+        } else {
+            Err(PrepareResult::SyntaxError)
+        }
+    }
+
+    fn is_aggregate(&self) -> bool {
+        matches!(self, Self::CountAll | Self::Aggregate(..))
+    }
+
+    /// Evaluates a scalar expression against a single row. Aggregates only
+    /// have a value per group, so they evaluate to `None` here.
+    fn evaluate(&self, row: &Row) -> Option<String> {
+        match self {
+            Self::Column(column) => row.value(*column).map(|value| value.to_string()),
+            Self::Literal(value) => value.clone(),
+            Self::Coalesce(args) => args.iter().find_map(|arg| arg.evaluate(row)),
+            Self::Function(function, column) => row
+                .value(*column)
+                .map(|value| apply_scalar(*function, &value.to_string())),
+            Self::CountAll | Self::Aggregate(..) => None,
+        }
+    }
+}
+
+/// Running state of an aggregate function over the values fed to it.
+struct Accumulator {
+    op: AggregateOp,
+    count: usize,
+    sum: u64,
+    total: f64,
+    result: Option<Value>,
+}
+
+impl Accumulator {
+    fn new(op: AggregateOp) -> Self {
+        Self {
+            op,
+            count: 0,
+            sum: 0,
+            total: 0.0,
+            result: None,
+        }
+    }
+
+    fn add(&mut self, value: Value) -> Result<(), Box<dyn Error>> {
+        self.count += 1;
+
+        match (self.op, value) {
+            (AggregateOp::Count, _) => {}
+            (AggregateOp::Min, value) => {
+                self.result = Some(match self.result.take() {
+                    Some(current) => current.min(value),
+                    None => value,
+                });
+            }
+            (AggregateOp::Max, value) => {
+                self.result = Some(match self.result.take() {
+                    Some(current) => current.max(value),
+                    None => value,
+                });
+            }
+            (AggregateOp::Sum, Value::Integer(value)) => {
+                self.sum = u64::try_from(value)
+                    .ok()
+                    .and_then(|value| self.sum.checked_add(value))
+                    .ok_or(MysqliteError::Overflow)?;
+            }
+            (AggregateOp::Avg, Value::Integer(value)) => self.total += value as f64,
+            (AggregateOp::Sum | AggregateOp::Avg, _) => {
+                return Err(format!("{} requires a numeric column", self.op).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Option<Value>, MysqliteError> {
+        match self.op {
+            AggregateOp::Count => Ok(Some(Value::Integer(self.count as i64))),
+            AggregateOp::Min | AggregateOp::Max => Ok(self.result),
+            _ if self.count == 0 => Ok(None),
+            AggregateOp::Sum => {
+                let sum = i64::try_from(self.sum).map_err(|_| MysqliteError::Overflow)?;
+                Ok(Some(Value::Integer(sum)))
+            }
+            AggregateOp::Avg => Ok(Some(Value::Real(self.total / self.count as f64))),
+        }
+    }
+}
+
+/// Column names and rendered values of a fully materialized query result.
+struct ResultSet {
+    names: Vec<String>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+impl ResultSet {
+    /// The widest a value is printed in column mode before it's cut short.
+    const MAX_COLUMN_WIDTH: usize = 30;
+
+    fn write<W>(&self, output: &mut W, mode: OutputMode) -> io::Result<()>
+    where
+        W: io::Write,
+    {
+        if !matches!(mode, OutputMode::Column) {
+            for (i, values) in self.rows.iter().enumerate() {
+                mode.write_row(output, &self.names, values, i == 0)?;
+            }
+            return Ok(());
+        }
+
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let rows: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|values| {
+                values
+                    .iter()
+                    .map(|value| Self::truncate(value.as_deref().unwrap_or("NULL")))
+                    .collect()
+            })
+            .collect();
+        let widths: Vec<usize> = self
+            .names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                rows.iter()
+                    .map(|values| values[i].chars().count())
+                    .fold(name.chars().count(), usize::max)
+            })
+            .collect();
+
+        let separators: Vec<String> = widths.iter().map(|&width| "-".repeat(width)).collect();
+        for values in std::iter::once(&self.names)
+            .chain(std::iter::once(&separators))
+            .chain(&rows)
+        {
+            let line: Vec<String> = values
+                .iter()
+                .zip(&widths)
+                .map(|(value, &width)| format!("{value:width$}"))
+                .collect();
+            writeln!(output, "{}", line.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// Cuts `value` down to `MAX_COLUMN_WIDTH` characters, ending in `...`
+    /// if anything was dropped.
+    fn truncate(value: &str) -> String {
+        if value.chars().count() <= Self::MAX_COLUMN_WIDTH {
+            return value.to_string();
+        }
+        let kept: String = value.chars().take(Self::MAX_COLUMN_WIDTH - 3).collect();
+        format!("{kept}...")
+    }
+}
+
+/// Returns the output column names for `projection`, where an empty
+/// projection selects every column.
+fn projection_names(projection: &[ProjectionExpr]) -> Vec<String> {
+    if projection.is_empty() {
+        ColumnSelector::ALL
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    } else {
+        projection.iter().map(ToString::to_string).collect()
+    }
+}
+
+/// Removes repeated rows, keeping the first occurrence of each.
+fn dedup_rows(rows: &mut Vec<Vec<Option<String>>>) {
+    let mut seen = HashSet::new();
+    rows.retain(|row| seen.insert(row.clone()));
+}
+
+/// Returns the byte offsets where `pattern` occurs in `s`, ignoring ASCII
+/// case, outside of any parentheses or `'quoted'` strings.
+fn top_level_matches<'a>(s: &'a str, pattern: &'a str) -> impl Iterator<Item = usize> + 'a {
+    (0..s.len())
+        .filter(move |&at| {
+            s.get(at..at + pattern.len())
+                .is_some_and(|candidate| candidate.eq_ignore_ascii_case(pattern))
+        })
+        .filter(move |&at| is_top_level(&s[..at]))
+}
+
+/// Strips `keyword` from the start of `s`, ignoring ASCII case.
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    s.get(..keyword.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(keyword))
+        .map(|_| &s[keyword.len()..])
+}
+
+/// Returns whether every parenthesis and quote opened in `prefix` is
+/// closed again.
+fn is_top_level(prefix: &str) -> bool {
+    let mut depth = 0;
+    let mut quoted = false;
+    for c in prefix.chars() {
+        match c {
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            _ => {}
+        }
+    }
+    depth == 0 && !quoted
+}
+
+/// Splits `s` on separator characters that aren't nested inside
+/// parentheses or `'quoted'` strings, trimming each part.
+fn split_top_level(s: &str, is_separator: fn(char) -> bool) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '\'' => quoted = !quoted,
+            _ if quoted => {}
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if depth == 0 && is_separator(c) => {
+                parts.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].trim());
+    parts
+}
+
+/// Applies a scalar function to a single value. Case conversion only maps
+/// ASCII letters; full Unicode case mapping is a separate feature.
+fn apply_scalar(f: FnName, val: &str) -> String {
+    match f {
+        FnName::Upper => val.to_ascii_uppercase(),
+        FnName::Lower => val.to_ascii_lowercase(),
+        FnName::Length => val.len().to_string(),
+        FnName::Substr { start, length } => {
+            // SQL positions are 1-based; anything before the first character
+            // starts the substring at the first character.
+            let skip = usize::try_from(start.saturating_sub(1)).unwrap_or(0);
+            let take = usize::try_from(length).unwrap_or(0);
+            val.chars().skip(skip).take(take).collect()
+        }
+    }
+}
+
+impl FromStr for WhereClause {
+    type Err = PrepareResult;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tokens = Tokens::new(s);
+        let clause = parse_or(&mut tokens)?;
+        if !tokens.rest().trim().is_empty() {
+            return Err(PrepareResult::SyntaxError);
+        }
+
+        Ok(clause)
+    }
+}
+
+/// Parses `OR`-separated terms; `OR` binds loosest.
+fn parse_or(tokens: &mut Tokens) -> Result<WhereClause, PrepareResult> {
+    let mut clause = parse_and(tokens)?;
+
+    while tokens
+        .peek_word()
+        .is_some_and(|w| w.eq_ignore_ascii_case("or"))
+    {
+        tokens.next_word();
+        let rhs = parse_and(tokens)?;
+        clause = WhereClause::Or(Box::new(clause), Box::new(rhs));
+    }
+
+    Ok(clause)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<WhereClause, PrepareResult> {
+    let mut clause = parse_not(tokens)?;
+
+    while tokens
+        .peek_word()
+        .is_some_and(|w| w.eq_ignore_ascii_case("and"))
+    {
+        tokens.next_word();
+        let rhs = parse_not(tokens)?;
+        clause = WhereClause::And(Box::new(clause), Box::new(rhs));
+    }
+
+    Ok(clause)
+}
+
+fn parse_not(tokens: &mut Tokens) -> Result<WhereClause, PrepareResult> {
+    if tokens
+        .peek_word()
+        .is_some_and(|w| w.eq_ignore_ascii_case("not"))
+    {
+        tokens.next_word();
+        return match parse_not(tokens)? {
+            WhereClause::Not(clause) => Ok(*clause),
+            clause => Ok(WhereClause::Not(Box::new(clause))),
+        };
+    }
+
+    parse_predicate(tokens)
+}
+
+fn parse_predicate(tokens: &mut Tokens) -> Result<WhereClause, PrepareResult> {
+    if tokens.peek() == Some('(') {
+        tokens.next_char();
+        let clause = parse_or(tokens)?;
+        if tokens.next_char() != Some(')') {
+            return Err(PrepareResult::SyntaxError);
+        }
+        return Ok(clause);
+    }
+
+    let column: ColumnSelector = tokens
+        .next_word()
+        .ok_or(PrepareResult::SyntaxError)?
+        .parse()?;
+    let operator = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+
+    if operator.eq_ignore_ascii_case("is") {
+        let mut word = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+        let negated = word.eq_ignore_ascii_case("not");
+        if negated {
+            word = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+        }
+        if !word.eq_ignore_ascii_case("null") {
+            return Err(PrepareResult::SyntaxError);
+        }
+        return Ok(if negated {
+            WhereClause::IsNotNull(column)
+        } else {
+            WhereClause::IsNull(column)
+        });
+    }
+
+    if operator.eq_ignore_ascii_case("in") {
+        let query = tokens
+            .next_parenthesized()
+            .ok_or(PrepareResult::SyntaxError)?
+            .trim();
+        let query = strip_keyword(query, "select").ok_or(PrepareResult::SyntaxError)?;
+        let query = prepare_compound(query)?;
+        if query.column_count() != Some(1) {
+            return Err(PrepareResult::SyntaxError);
+        }
+
+        return Ok(WhereClause::InSubquery {
+            column,
+            query: Box::new(query),
+        });
+    }
+    let literal = next_literal(tokens)?;
+
+    if operator.eq_ignore_ascii_case("like") {
+        return Ok(WhereClause::Like {
+            column,
+            pattern: literal.to_string(),
+        });
+    }
+
+    if operator.eq_ignore_ascii_case("between") {
+        if !tokens
+            .next_word()
+            .is_some_and(|word| word.eq_ignore_ascii_case("and"))
+        {
+            return Err(PrepareResult::SyntaxError);
+        }
+        let high = next_literal(tokens)?;
+        return Ok(WhereClause::Between {
+            column,
+            low: literal_value(column, literal)?,
+            high: literal_value(column, high)?,
+        });
+    }
+
+    let op = operator.parse()?;
+    let value = literal_value(column, literal)?;
+
+    Ok(WhereClause::Compare { column, op, value })
+}
+
+/// Reads a literal word, dropping the quotes around a string.
+fn next_literal<'a>(tokens: &mut Tokens<'a>) -> Result<&'a str, PrepareResult> {
+    let literal = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+    Ok(literal
+        .strip_prefix('\'')
+        .and_then(|l| l.strip_suffix('\''))
+        .unwrap_or(literal))
+}
+
+/// Types `literal` to compare against `column`.
+fn literal_value(column: ColumnSelector, literal: &str) -> Result<Value, PrepareResult> {
+    if column.is_text() {
+        Ok(Value::Text(literal.to_string()))
+    } else {
+        Ok(Value::Integer(
+            literal.parse().map_err(|_| PrepareResult::TypeMismatch)?,
+        ))
+    }
+}
+
+impl Display for WhereClause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Like { column, pattern } => write!(f, "{column} like '{pattern}'"),
+            Self::Compare { column, op, value } => match value {
+                Value::Text(value) => write!(f, "{column} {op} '{value}'"),
+                value => write!(f, "{column} {op} {value}"),
+            },
+            Self::Between { column, low, high } => match (low, high) {
+                (Value::Text(low), Value::Text(high)) => {
+                    write!(f, "{column} between '{low}' and '{high}'")
+                }
+                (low, high) => write!(f, "{column} between {low} and {high}"),
+            },
+            Self::InSubquery { column, .. } | Self::In { column, .. } => {
+                write!(f, "{column} in (subquery)")
+            }
+            Self::IsNull(column) => write!(f, "{column} is null"),
+            Self::IsNotNull(column) => write!(f, "{column} is not null"),
+            Self::Not(clause) => write!(f, "not {clause}"),
+            Self::And(lhs, rhs) => write!(f, "({lhs} and {rhs})"),
+            Self::Or(lhs, rhs) => write!(f, "({lhs} or {rhs})"),
+        }
+    }
+}
+
+impl WhereClause {
+    /// For an `id = n` or `id between a and b` clause, returns the ids that
+    /// can match, which is empty if none of them can be an id.
+    fn id_lookup(&self) -> Option<RangeInclusive<u32>> {
+        let (low, high) = match self {
+            Self::Compare {
+                column: ColumnSelector::Id,
+                op: CompareOp::Eq,
+                value: Value::Integer(id),
+            } => (*id, *id),
+            Self::Between {
+                column: ColumnSelector::Id,
+                low: Value::Integer(low),
+                high: Value::Integer(high),
+            } => (*low, *high),
+            _ => return None,
+        };
+
+        let clamp = |id: i64| id.clamp(0, u32::MAX.into()) as u32;
+        if low > high || high < 0 || low > u32::MAX.into() {
+            return Some(RangeInclusive::new(1, 0));
+        }
+        Some(clamp(low)..=clamp(high))
+    }
+
+    /// Returns the subqueries this clause runs before scanning.
+    fn subqueries(&self) -> Vec<&Statement> {
+        match self {
+            Self::InSubquery { query, .. } => vec![query],
+            Self::Not(clause) => clause.subqueries(),
+            Self::And(lhs, rhs) | Self::Or(lhs, rhs) => {
+                let mut subqueries = lhs.subqueries();
+                subqueries.extend(rhs.subqueries());
+                subqueries
+            }
+            Self::Like { .. }
+            | Self::Compare { .. }
+            | Self::Between { .. }
+            | Self::In { .. }
+            | Self::IsNull(_)
+            | Self::IsNotNull(_) => vec![],
+        }
+    }
+
+    fn matches(&self, row: &Row) -> bool {
+        self.evaluate(row) == Some(true)
+    }
+
+    /// Evaluates the predicate with SQL's three-valued logic: comparing a
+    /// NULL field is unknown (`None`), and negating unknown stays unknown.
+    fn evaluate(&self, row: &Row) -> Option<bool> {
+        match self {
+            Self::Like { column, pattern } => row
+                .value(*column)
+                .map(|value| like_match(&value.to_string(), pattern)),
+            Self::Compare { column, op, value } => {
+                row.value(*column).map(|field| op.holds(field.cmp(value)))
+            }
+            Self::Between { column, low, high } => row
+                .value(*column)
+                .map(|field| *low <= field && field <= *high),
+            Self::In {
+                column,
+                values,
+                has_null,
+            } => {
+                let value = row.value(*column)?;
+                if values.contains(&value.to_string()) {
+                    Some(true)
+                } else if *has_null {
+                    None
+                } else {
+                    Some(false)
+                }
+            }
+            Self::IsNull(column) => Some(row.is_null(*column)),
+            Self::IsNotNull(column) => Some(!row.is_null(*column)),
+            // Subqueries are bound before evaluation; see `Table::bind`.
+            Self::InSubquery { .. } => None,
+            Self::Not(clause) => clause.evaluate(row).map(|matched| !matched),
+            Self::And(lhs, rhs) => match lhs.evaluate(row) {
+                Some(false) => Some(false),
+                lhs => match rhs.evaluate(row) {
+                    Some(false) => Some(false),
+                    rhs => lhs.and(rhs),
+                },
+            },
+            Self::Or(lhs, rhs) => match lhs.evaluate(row) {
+                Some(true) => Some(true),
+                lhs => match rhs.evaluate(row) {
+                    Some(true) => Some(true),
+                    rhs => lhs.and(rhs),
+                },
+            },
+        }
+    }
+}
+
+/// Matches `value` against a SQL `LIKE` pattern, where `%` matches any run of
+/// characters, `_` matches exactly one, and `\` escapes the next character.
+///
+/// Runs in O(n·m) time: on a mismatch only the most recent `%` is retried,
+/// one character further on, since any earlier `%` could only match less.
+fn like_match(value: &str, pattern: &str) -> bool {
+    /// One pattern element, with escapes already resolved. `None` stands
+    /// for `%` and `Some(None)` for `_`.
+    type Element = Option<Option<char>>;
+
+    let mut elements: Vec<Element> = vec![];
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        elements.push(match c {
+            '%' => None,
+            '_' => Some(None),
+            // A trailing `\` has nothing to escape and matches itself.
+            '\\' => Some(Some(chars.next().unwrap_or('\\'))),
+            c => Some(Some(c)),
+        });
+    }
+    let value: Vec<char> = value.chars().collect();
+
+    let (mut v, mut p) = (0, 0);
+    // Where to resume after the last `%`: the element after it and the
+    // value position it is tried against next.
+    let mut retry = None;
+    while v < value.len() {
+        match elements.get(p) {
+            Some(None) => {
+                p += 1;
+                retry = Some((p, v));
+            }
+            Some(Some(expected)) if expected.is_none_or(|c| c == value[v]) => {
+                p += 1;
+                v += 1;
+            }
+            _ => match retry {
+                Some((after_percent, start)) => {
+                    p = after_percent;
+                    v = start + 1;
+                    retry = Some((after_percent, v));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    elements[p..].iter().all(Option::is_none)
+}
+
+/// Maps `''` to the empty string, which a whitespace-separated field can't
+/// otherwise spell.
+fn unquote_empty(value: &str) -> &str {
+    if value == "''" { "" } else { value }
+}
+
+/// Shows the empty string as `''` so that it stands apart from a missing
+/// field in tuple output.
+fn quote_empty(value: &str) -> &str {
+    if value.is_empty() { "''" } else { value }
+}
+
+/// Maps the `null` keyword (in any case) to `None`.
+fn nullable(value: &str) -> Option<&str> {
+    (!value.eq_ignore_ascii_case("null")).then_some(value)
+}
+
+/// The remaining input of an arithmetic expression, consumed by `eval_expr`.
+struct Tokens<'a> {
+    input: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input.trim_start().chars().next()
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        self.input = self.input.trim_start();
+        let c = self.input.chars().next()?;
+        self.input = &self.input[c.len_utf8()..];
+        Some(c)
+    }
+
+    fn next_integer(&mut self) -> Option<i64> {
+        self.input = self.input.trim_start();
+        let end = self
+            .input
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.input.len());
+        let integer = self.input[..end].parse().ok()?;
+        self.input = &self.input[end..];
+        Some(integer)
+    }
+
+    /// Returns the next parenthesis, `'quoted'` string or whitespace
+    /// delimited word without consuming it.
+    fn peek_word(&self) -> Option<&'a str> {
+        let input = self.input.trim_start();
+        let len = match input.chars().next()? {
+            '(' | ')' => 1,
+            '\'' => input[1..].find('\'').map_or(input.len(), |end| end + 2),
+            _ => input
+                .find(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .unwrap_or(input.len()),
+        };
+        Some(&input[..len])
+    }
+
+    fn next_word(&mut self) -> Option<&'a str> {
+        let word = self.peek_word()?;
+        self.input = &self.input.trim_start()[word.len()..];
+        Some(word)
+    }
+
+    /// Consumes a parenthesized group and returns what's inside it.
+    fn next_parenthesized(&mut self) -> Option<&'a str> {
+        let input = self.input.trim_start().strip_prefix('(')?;
+        let end = top_level_matches(input, ")").next()?;
+        self.input = &input[end + 1..];
+        Some(&input[..end])
+    }
+
+    fn rest(&self) -> &'a str {
+        self.input
+    }
+}
+
+/// Evaluates an integer expression built from `+`, `-`, `*`, `/` and
+/// parentheses, stopping at the first token that can't continue it.
+fn eval_expr(tokens: &mut Tokens) -> Result<i64, PrepareResult> {
+    let mut value = eval_term(tokens)?;
+
+    while let Some(op @ ('+' | '-')) = tokens.peek() {
+        tokens.next_char();
+        let rhs = eval_term(tokens)?;
+        value = match op {
+            '+' => value.checked_add(rhs),
+            _ => value.checked_sub(rhs),
+        }
+        .ok_or(PrepareResult::SyntaxError)?;
+    }
+
+    Ok(value)
+}
+
+fn eval_term(tokens: &mut Tokens) -> Result<i64, PrepareResult> {
+    let mut value = eval_factor(tokens)?;
+
+    while let Some(op @ ('*' | '/')) = tokens.peek() {
+        tokens.next_char();
+        let rhs = eval_factor(tokens)?;
+        value = match op {
+            '*' => value.checked_mul(rhs).ok_or(PrepareResult::SyntaxError)?,
+            _ if rhs == 0 => return Err(PrepareResult::DivisionByZero),
+            _ => value.checked_div(rhs).ok_or(PrepareResult::SyntaxError)?,
+        };
+    }
+
+    Ok(value)
+}
+
+fn eval_factor(tokens: &mut Tokens) -> Result<i64, PrepareResult> {
+    match tokens.peek() {
+        Some('(') => {
+            tokens.next_char();
+            let value = eval_expr(tokens)?;
+            if tokens.next_char() != Some(')') {
+                return Err(PrepareResult::SyntaxError);
+            }
+            Ok(value)
+        }
+        Some('-') => {
+            tokens.next_char();
+            eval_factor(tokens)?
+                .checked_neg()
+                .ok_or(PrepareResult::SyntaxError)
+        }
+        _ => tokens.next_integer().ok_or(PrepareResult::SyntaxError),
+    }
+}
+
+impl Display for Row {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+
+        if self.is_null(ColumnSelector::Id) {
+            write!(f, "NULL")?;
+        } else {
+            write!(f, "{}", self.id)?;
+        }
+
+        if self.is_null(ColumnSelector::Username) {
+            write!(f, " NULL")?;
+        } else {
+            write!(f, " {}", quote_empty(self.username_str()))?;
+        }
+
+        if self.is_null(ColumnSelector::Email) {
+            write!(f, " NULL")?;
+        } else {
+            write!(f, " {}", quote_empty(self.email_str()))?;
+        }
+
+        if self.is_null(ColumnSelector::Age) {
+            write!(f, " NULL")?;
+        } else {
+            write!(f, " {}", self.age)?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+struct Pager {
+    /// The database file, or `None` for a table kept only in memory, whose
+    /// pages all stay cached.
+    file: Option<std::fs::File>,
+    path: PathBuf,
+    mode: u32,
+    /// How many bytes each page holds.
+    size: usize,
+    /// The cached pages, which need not be contiguous.
+    pages: HashMap<usize, Box<[u8]>>,
+    /// The pages that may differ from the file, added whenever a page is
+    /// handed out for writing.
+    dirty: HashSet<usize>,
+    /// How many pages the file has or will have once flushed, one past the
+    /// highest page ever touched.
+    page_count: usize,
+    /// How many pages to keep cached, if limited. Only unmodified pages are
+    /// evicted, so dirty ones can push the cache past it until flushed.
+    cache_capacity: Option<usize>,
+    /// Where to trace page accesses and flushes, if anywhere.
+    log: Option<Box<dyn Write>>,
+}
+
+/// Diagnostic snapshot of one page slot, as printed by `.pageinfo`.
+struct PageInfo {
+    index: usize,
+    loaded: bool,
+    dirty: bool,
+    head: Vec<u8>,
+}
+
+impl Display for PageInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "page: {}", self.index)?;
+        writeln!(f, "loaded: {}", self.loaded)?;
+        writeln!(f, "dirty: {}", self.dirty)?;
+        for (i, chunk) in self.head.chunks(16).enumerate() {
+            let bytes: Vec<_> = chunk.iter().map(|byte| format!("{byte:02x}")).collect();
+            writeln!(f, "{:04x}: {}", i * 16, bytes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Size and cache figures printed by `.stats`.
+struct Stats {
+    rows: usize,
+    page_size: usize,
+    pages_on_disk: usize,
+    pages_cached: usize,
+    pages_dirty: usize,
+    /// Bytes of row data per byte of the pages holding them, which only
+    /// falls short of 1 by the slack at the end of each page and the
+    /// unfilled part of the last one.
+    fill: f64,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows: {}", self.rows)?;
+        writeln!(f, "page size: {}", self.page_size)?;
+        writeln!(f, "pages on disk: {}", self.pages_on_disk)?;
+        writeln!(f, "pages cached: {}", self.pages_cached)?;
+        writeln!(f, "pages dirty: {}", self.pages_dirty)?;
+        writeln!(f, "fill: {:.1}%", self.fill * 100.0)
+    }
+}
+
+/// The bytes stored for one row, including the padding of its text fields.
+struct RawRow {
+    bytes: Vec<u8>,
+    schema: Schema,
+}
+
+impl Display for RawRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = |bytes: &[u8]| {
+            let bytes: Vec<_> = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+            bytes.join(" ")
+        };
+        writeln!(
+            f,
+            "nulls: {}",
+            hex(&self.bytes[Row::NULLS_OFFSET..Row::NULLS_OFFSET + Row::NULLS_SIZE])
+        )?;
+        for column in ColumnSelector::ALL {
+            let (offset, size) = self.schema.field(column);
+            writeln!(f, "{column}: {}", hex(&self.bytes[offset..offset + size]))?;
+        }
+        Ok(())
+    }
+}
+
+impl Pager {
+    const DEFAULT_MODE: u32 = 0o0600;
+
+    /// Opens the database file as `options` describe, with pages of the
+    /// size their schema asks for until `set_size` says otherwise. A
+    /// read-only file is opened under a shared lock so that it can't be
+    /// opened for writing at the same time.
+    fn new(path: impl AsRef<Path>, options: &TableOptions) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref().to_path_buf();
+        let mode = options.mode;
+        let file = if options.in_memory {
+            None
+        } else if options.read_only {
+            let file = OpenOptions::new().read(true).open(&path)?;
+            Self::lock(&file, true)?;
+            Some(file)
+        } else {
+            Some(Self::open(&path, mode, options.create_if_missing)?)
+        };
+
+        let mut pager = Self {
+            file,
+            path,
+            mode,
+            size: 0,
+            pages: HashMap::new(),
+            dirty: HashSet::new(),
+            page_count: 0,
+            cache_capacity: options.cache_capacity,
+            log: None,
+        };
+        pager.set_size(options.schema.page_size)?;
+        Ok(pager)
+    }
+
+    /// Sets how many bytes each page holds, which for an existing file is
+    /// only known once its header has been read. No page may be loaded yet.
+    fn set_size(&mut self, size: usize) -> Result<(), MysqliteError> {
+        self.size = size;
+        let page_count = self.file_length()?.div_ceil(size as u64);
+        self.page_count = usize::try_from(page_count).map_err(|_| MysqliteError::Overflow)?;
+        Ok(())
+    }
+
+    /// Opens the file for writing, creating it with permission bits `mode`
+    /// if it's missing and `create` is set, and takes an exclusive lock on
+    /// it, which is released when the file is closed.
+    fn open(path: &Path, mode: u32, create: bool) -> Result<std::fs::File, Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .truncate(false)
+            .mode(mode)
+            .open(path)?;
+
+        Self::lock(&file, false)?;
+        Ok(file)
+    }
+
+    fn lock(file: &std::fs::File, shared: bool) -> Result<(), Box<dyn Error>> {
+        let locked = if shared {
+            file.try_lock_shared()
+        } else {
+            file.try_lock()
+        };
+
+        match locked {
+            Ok(()) => Ok(()),
+            Err(std::fs::TryLockError::WouldBlock) => Err(MysqliteError::DatabaseLocked.into()),
+            Err(std::fs::TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    /// Returns page `page_num` for writing, marking it dirty.
+    fn get_page(&mut self, page_num: usize) -> Result<&mut [u8], MysqliteError> {
+        self.load_page(page_num)?;
+        self.dirty.insert(page_num);
+
+        let page = self
+            .pages
+            .get_mut(&page_num)
+            .expect("page must be initialized before returning");
+
+        Ok(page)
+    }
+
+    /// Returns page `page_num` for reading, leaving its dirty flag alone.
+    fn get_page_readonly(&mut self, page_num: usize) -> Result<&[u8], MysqliteError> {
+        self.load_page(page_num)?;
+
+        let page = self
+            .pages
+            .get(&page_num)
+            .expect("page must be initialized before returning");
+
+        Ok(page)
+    }
+
+    fn load_page(&mut self, page_num: usize) -> Result<(), MysqliteError> {
+        self.page_count = self.page_count.max(page_num + 1);
+
+        if self.pages.contains_key(&page_num) {
+            self.trace(format_args!("page {page_num}: hit"));
+        } else {
+            let mut page = vec![0; self.size].into_boxed_slice();
+
+            let file_length = self.file_length()?;
+            let num_pages = file_length.div_ceil(self.size as u64);
+
+            if let Some(file) = &mut self.file
+                && (page_num as u64) < num_pages
+            {
+                let offset = page_num as u64 * self.size as u64;
+                let bytes_to_read = usize::try_from(std::cmp::min(
+                    self.size as u64,
+                    file_length.saturating_sub(offset),
+                ))
+                .map_err(|_| MysqliteError::Overflow)?;
+
+                // The cursor may have been left anywhere by an earlier read
+                // or flush, so position it explicitly.
+                file.seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.read_exact(&mut page[..bytes_to_read]))
+                    .map_err(MysqliteError::read_failed(page_num))?;
+                self.trace(format_args!(
+                    "page {page_num}: miss, read {bytes_to_read} bytes"
+                ));
+            } else {
+                self.trace(format_args!("page {page_num}: miss, new page"));
+            }
+
+            self.pages.insert(page_num, page);
+            self.evict(page_num);
+        }
+
+        Ok(())
+    }
+
+    /// Drops unmodified pages other than `keep` until the cache fits its
+    /// capacity or only dirty pages are left.
+    fn evict(&mut self, keep: usize) {
+        let Some(capacity) = self.cache_capacity else {
+            return;
+        };
+
+        while self.pages.len() > capacity {
+            let Some(&victim) = self
+                .pages
+                .keys()
+                .find(|&&index| index != keep && !self.dirty.contains(&index))
+            else {
+                break;
+            };
+            self.pages.remove(&victim);
+            self.trace(format_args!("page {victim}: evicted"));
+        }
+    }
+
+    /// Fills `buf` from `offset` within page `page_num`, taking the bytes
+    /// from the cache if the page is loaded and from the file otherwise,
+    /// without caching the page. Pages outside the cache are never dirty,
+    /// so the file holds their latest contents, and in memory they were
+    /// never written at all.
+    fn peek(&mut self, page_num: usize, offset: usize, buf: &mut [u8]) -> io::Result<()> {
+        if let Some(page) = self.pages.get(&page_num) {
+            buf.copy_from_slice(&page[offset..offset + buf.len()]);
+            return Ok(());
+        }
+        let Some(file) = &mut self.file else {
+            buf.fill(0);
+            return Ok(());
+        };
+
+        file.seek(SeekFrom::Start((page_num * self.size + offset) as u64))?;
+        file.read_exact(buf)?;
+        self.trace(format_args!("page {page_num}: peeked {} bytes", buf.len()));
+        Ok(())
+    }
+
+    /// Writes the first `size` bytes of page `index` back to the file, if
+    /// it was modified since it was loaded or last flushed. Pages in memory
+    /// have nowhere to go and stay dirty.
+    fn flush_page(&mut self, index: usize, size: usize) -> io::Result<()> {
+        let (Some(page), Some(file)) = (self.pages.get(&index), &mut self.file) else {
+            return Ok(());
+        };
+        if !self.dirty.contains(&index) {
+            return Ok(());
+        }
+
+        let offset = (index as u64) * (self.size as u64);
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&page[..size])?;
+        self.dirty.remove(&index);
+        self.trace(format_args!("page {index}: flushed {size} bytes"));
+        Ok(())
+    }
+
+    /// Writes one line to the log, if enabled. Failing to log is not worth
+    /// failing the operation being traced, so errors are ignored.
+    fn trace(&mut self, message: fmt::Arguments<'_>) {
+        if let Some(log) = &mut self.log {
+            let _ = writeln!(log, "pager: {message}");
+        }
+    }
+
+    /// Syncs the file's contents and length to disk.
+    fn sync_data(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+            self.trace(format_args!("synced data"));
+        }
+        Ok(())
+    }
+
+    /// Syncs the file's contents and all of its metadata to disk.
+    fn sync_all(&mut self) -> io::Result<()> {
+        if let Some(file) = &self.file {
+            file.sync_all()?;
+            self.trace(format_args!("synced all"));
+        }
+        Ok(())
+    }
+
+    /// Returns the current length of the file on disk, which is 0 for a
+    /// table in memory.
+    fn file_length(&self) -> Result<u64, MysqliteError> {
+        self.file
+            .as_ref()
+            .map_or(Ok(0), |file| file.metadata().map(|metadata| metadata.len()))
+            .map_err(MysqliteError::flush_failed(None))
+    }
+
+    /// Returns how many pages the pager tracks, whether loaded or not.
+    fn page_count(&self) -> usize {
+        self.page_count
+    }
+
+    /// Returns how many pages the file holds, counting a partial last page,
+    /// which lags behind `page_count` until new pages are flushed.
+    fn file_page_count(&self) -> io::Result<usize> {
+        let length = match &self.file {
+            Some(file) => file.metadata()?.len(),
+            None => 0,
+        };
+        usize::try_from(length.div_ceil(self.size as u64)).map_err(io::Error::other)
+    }
+
+    /// Describes page `index` without loading it into the cache, or returns
+    /// `None` if there is no such page.
+    fn page_info(&self, index: usize) -> io::Result<Option<PageInfo>> {
+        const HEAD_SIZE: usize = 64;
+
+        if index >= self.page_count {
+            return Ok(None);
+        }
+
+        let page = self.pages.get(&index);
+        let head = match (page, &self.file) {
+            (Some(page), _) => page[..HEAD_SIZE].to_vec(),
+            (None, Some(file)) => {
+                let mut file = file;
+                file.seek(SeekFrom::Start((index * self.size) as u64))?;
+                let mut head = vec![];
+                file.take(HEAD_SIZE as u64).read_to_end(&mut head)?;
+                head
+            }
+            (None, None) => vec![0; HEAD_SIZE],
+        };
+
+        Ok(Some(PageInfo {
+            index,
+            loaded: page.is_some(),
+            dirty: self.dirty.contains(&index),
+            head,
+        }))
+    }
+}
+
+/// How `Table::with_options` opens a table and how it's persisted.
+struct TableOptions {
+    mode: u32,
+    create_if_missing: bool,
+    read_only: bool,
+    in_memory: bool,
+    atomic_close: bool,
+    sync_mode: SyncMode,
+    cache_capacity: Option<usize>,
+    max_rows: Option<usize>,
+    schema: Schema,
+}
+
+impl Default for TableOptions {
+    fn default() -> Self {
+        Self {
+            mode: Pager::DEFAULT_MODE,
+            create_if_missing: true,
+            read_only: false,
+            in_memory: false,
+            atomic_close: false,
+            sync_mode: SyncMode::default(),
+            cache_capacity: None,
+            max_rows: None,
+            schema: Schema::default(),
+        }
+    }
+}
+
+impl TableOptions {
+    /// Sets the permission bits used when creating a new database file.
+    fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets whether a missing database file is created rather than
+    /// reported as an error.
+    fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Sets whether the table rejects every modification with
+    /// `MysqliteError::ReadOnly`. The file must already exist.
+    fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Sets whether the table is kept in memory instead of a file, so that
+    /// the path it's opened with is ignored, no lock is taken and nothing
+    /// is left once it's closed.
+    fn in_memory(mut self, in_memory: bool) -> Self {
+        self.in_memory = in_memory;
+        self
+    }
+
+    /// Sets whether closing writes a snapshot and renames it into place.
+    fn atomic_close(mut self, atomic_close: bool) -> Self {
+        self.atomic_close = atomic_close;
+        self
+    }
+
+    fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Limits how many pages are cached at once, which is unlimited by
+    /// default.
+    fn cache_capacity(mut self, pages: usize) -> Self {
+        self.cache_capacity = Some(pages);
+        self
+    }
+
+    /// Limits how many rows the table holds, past which inserting fails
+    /// with `MysqliteError::TableFull`. There is no limit by default.
+    fn max_rows(mut self, rows: usize) -> Self {
+        self.max_rows = Some(rows);
+        self
+    }
+
+    /// Sets the page size and the sizes of the text columns for a new
+    /// database file. A file that already holds a table keeps the sizes it
+    /// was created with.
+    fn schema(mut self, schema: Schema) -> Self {
+        self.schema = schema;
+        self
+    }
+
+    /// Opens the table at `path` with these options.
+    fn open(self, path: impl AsRef<Path>) -> Result<Table, Box<dyn Error>> {
+        Table::with_options(path, self)
+    }
+}
+
+struct Table {
+    row_count: usize,
+    pager: Pager,
+    schema: Schema,
+    atomic_close: bool,
+    readonly: bool,
+    sync_mode: SyncMode,
+    max_rows: Option<usize>,
+    /// Set once `close` has been called, whether or not it succeeded.
+    closed: bool,
+}
+
+impl Table {
+    /// The page the rows start at, after the header.
+    const FIRST_PAGE: usize = 1;
+
+    fn with_options(path: impl AsRef<Path>, options: TableOptions) -> Result<Self, Box<dyn Error>> {
+        options.schema.validate()?;
+        let mut pager = Pager::new(path, &options)?;
+
+        let mut version_1_rows = None;
+        let stored_schema = match &pager.file {
+            Some(file) => Schema::read_header(file)?,
+            None => None,
+        };
+        let schema = match (stored_schema, &pager.file) {
+            (Some(schema), _) => schema,
+            // A file without a header is from version 1. Its rows are
+            // upgraded as they're read, and the file is rewritten in the
+            // current format when the table is closed.
+            (None, Some(file)) if pager.file_length()? > 0 => {
+                version_1_rows = Some(Self::read_version_1(file)?);
+                Schema::default()
+            }
+            // The header of a new file is written along with its rows.
+            (None, _) => options.schema,
+        };
+        pager.set_size(schema.page_size)?;
+        if stored_schema.is_none() && !options.read_only {
+            pager.get_page(0)?.copy_from_slice(&schema.header());
+        }
+
+        let row_count = match &version_1_rows {
+            Some(rows) => rows.len(),
+            None => {
+                let file_length = usize::try_from(pager.file_length()?)?;
+                let rows_length = file_length.saturating_sub(Self::FIRST_PAGE * schema.page_size);
+                let full_page_count = rows_length / schema.page_size;
+                let trailing_length = rows_length % schema.page_size;
+                if !trailing_length.is_multiple_of(schema.row_size()) {
+                    return Err("corrupt database (partial row)".into());
+                }
+
+                full_page_count * schema.rows_per_page() + trailing_length / schema.row_size()
+            }
+        };
+
+        let mut table = Self {
+            row_count,
+            pager,
+            schema,
+            atomic_close: options.atomic_close,
+            readonly: options.read_only,
+            sync_mode: options.sync_mode,
+            max_rows: options.max_rows,
+            closed: false,
+        };
+
+        // Every page is rewritten, so none of the old layout is read again.
+        let row_size = schema.row_size();
+        for (index, row) in version_1_rows.iter().flatten().enumerate() {
+            let (page_num, byte_offset) = table.row_location(index);
+            row.serialize(
+                &schema,
+                &mut table.pager.get_page(page_num)?[byte_offset..byte_offset + row_size],
+            );
+        }
+
+        Ok(table)
+    }
+
+    /// Reads every row of a version 1 file, which packs as many rows into
+    /// each page as fit, in the order they were inserted, and returns them
+    /// ordered by id.
+    fn read_version_1(file: &std::fs::File) -> Result<Vec<Row>, Box<dyn Error>> {
+        let mut file = file;
+        file.seek(SeekFrom::Start(0))?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let (full_pages, trailing) =
+            bytes.split_at(bytes.len() - bytes.len() % Schema::DEFAULT_PAGE_SIZE);
+        if !trailing.len().is_multiple_of(Row::VERSION_1_SIZE) {
+            return Err("corrupt database (partial row)".into());
+        }
+
+        let mut rows: Vec<_> = full_pages
+            .chunks(Schema::DEFAULT_PAGE_SIZE)
+            .chain([trailing])
+            .flat_map(|page| page.chunks_exact(Row::VERSION_1_SIZE))
+            .map(Row::deserialize_version_1)
+            .collect();
+        rows.sort_by_key(|row| row.id);
+        Ok(rows)
+    }
+
+    /// Persists the table, syncing as thoroughly as `sync_mode` asks. A
+    /// failed close isn't retried when the table is dropped, so its error
+    /// is reported once.
+    fn close(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.sync_mode {
+            SyncMode::Full => self.close_full_sync(),
+            sync_mode => {
+                self.closed = true;
+                self.close_with(sync_mode)
+            }
+        }
+    }
+
+    /// Persists the table like `close`, but syncs the file's metadata along
+    /// with its data whatever `sync_mode` says.
+    fn close_full_sync(&mut self) -> Result<(), Box<dyn Error>> {
+        self.closed = true;
+        self.close_with(SyncMode::Full)
+    }
+
+    fn close_with(&mut self, sync_mode: SyncMode) -> Result<(), Box<dyn Error>> {
+        if self.readonly || self.pager.file.is_none() {
+            Ok(())
+        } else if self.atomic_close {
+            self.close_atomic()
+        } else {
+            Ok(self.close_in_place(sync_mode)?)
+        }
+    }
+
+    /// Returns whether the file already holds exactly the table's rows, so
+    /// that closing has nothing to write.
+    fn is_saved(&self) -> Result<bool, MysqliteError> {
+        let file_length = self.pager.file_length()?;
+        Ok(self.pager.dirty.is_empty() && file_length == self.file_length())
+    }
+
+    /// Returns the length of the file once every row has been written.
+    fn file_length(&self) -> u64 {
+        self.pages()
+            .last()
+            .map_or(0, |(index, size)| index * self.schema.page_size + size) as u64
+    }
+
+    /// Returns each page of the file, from the header through the last one
+    /// holding rows, and how many of its bytes are in use.
+    fn pages(&self) -> impl Iterator<Item = (usize, usize)> + use<> {
+        let rows_per_page = self.schema.rows_per_page();
+        let full_page_count = self.row_count / rows_per_page;
+        let additional_row_count = self.row_count % rows_per_page;
+        let trailing_page = Self::FIRST_PAGE + full_page_count;
+        let page_size = self.schema.page_size;
+        (0..trailing_page).map(move |i| (i, page_size)).chain(
+            (additional_row_count > 0)
+                .then_some((trailing_page, additional_row_count * self.schema.row_size())),
+        )
+    }
+
+    fn close_in_place(&mut self, sync_mode: SyncMode) -> Result<(), MysqliteError> {
+        // Leave an unmodified file alone entirely, down to its mtime.
+        if self.is_saved()? {
+            return Ok(());
+        }
+
+        let length = self.file_length();
+        let file_length = self.pager.file_length()?;
+
+        for (index, size) in self.pages() {
+            self.pager
+                .flush_page(index, size)
+                .map_err(MysqliteError::flush_failed(Some(index)))?;
+        }
+
+        // Rows were removed, so drop whatever is left past the last one.
+        if file_length > length
+            && let Some(file) = &self.pager.file
+        {
+            file.set_len(length)
+                .map_err(MysqliteError::flush_failed(None))?;
+        }
+
+        match sync_mode {
+            SyncMode::Data => self.pager.sync_data(),
+            SyncMode::Full => self.pager.sync_all(),
+            SyncMode::None => Ok(()),
+        }
+        .map_err(MysqliteError::flush_failed(None))
+    }
+
+    /// Writes the table to a sibling temp file and renames it over the
+    /// original, so a crash part-way through never leaves a torn database.
+    fn close_atomic(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.is_saved()? {
+            return Ok(());
+        }
+
+        let mut temp_path = self.pager.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        // The snapshot is locked before it takes the original's place, so
+        // no other process can open it unlocked in between.
+        let file = self.write_snapshot(&temp_path)?;
+        std::fs::rename(&temp_path, &self.pager.path)?;
+
+        let dir = match self.pager.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(dir)?.sync_all()?;
+
+        self.pager.file = Some(file);
+        self.pager.dirty.clear();
+        Ok(())
+    }
+
+    /// Writes a complete, synced copy of the table to `path`, and returns
+    /// it locked like the database file.
+    fn write_snapshot(&self, path: &Path) -> Result<std::fs::File, Box<dyn Error>> {
+        std::fs::copy(&self.pager.path, path).map_err(MysqliteError::flush_failed(None))?;
+        let mut file = Pager::open(path, self.pager.mode, false)?;
+
+        for (index, size) in self.pages() {
+            if let Some(page) = self.pager.pages.get(&index)
+                && self.pager.dirty.contains(&index)
+            {
+                file.seek(SeekFrom::Start((index * self.schema.page_size) as u64))
+                    .and_then(|_| file.write_all(&page[..size]))
+                    .map_err(MysqliteError::flush_failed(Some(index)))?;
+            }
+        }
+
+        file.set_len(self.file_length())
+            .and_then(|()| file.sync_all())
+            .map_err(MysqliteError::flush_failed(None))?;
+        Ok(file)
+    }
+
+    /// Removes every row, returning how many there were. The file is cut
+    /// down to size when the table is closed.
+    fn clear(&mut self) -> Result<usize, MysqliteError> {
+        self.check_writable()?;
+
+        let count = self.row_count;
+        self.row_count = 0;
+        // Keep the header, which a new or upgraded file has yet to write.
+        self.pager
+            .pages
+            .retain(|&index, _| index < Self::FIRST_PAGE);
+        self.pager.dirty.retain(|&index| index < Self::FIRST_PAGE);
+        self.pager.page_count = Self::FIRST_PAGE;
+        Ok(count)
+    }
+
+    /// Returns whether the table holds as many rows as `max_rows` allows.
+    fn is_full(&self) -> bool {
+        self.max_rows
+            .is_some_and(|max_rows| self.row_count >= max_rows)
+    }
+
+    fn check_writable(&self) -> Result<(), MysqliteError> {
+        if self.readonly {
+            return Err(MysqliteError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Returns the page holding row `index` and the row's byte offset
+    /// within that page.
+    fn row_location(&self, index: usize) -> (usize, usize) {
+        let rows_per_page = self.schema.rows_per_page();
+        let page_num = Self::FIRST_PAGE + index / rows_per_page;
+        let byte_offset = (index % rows_per_page) * self.schema.row_size();
+        (page_num, byte_offset)
+    }
+
+    /// Inserts `row` after every row whose id is less than or equal to its
+    /// own, shifting later rows up by one slot so the table stays sorted,
+    /// and returns the index it landed at. NULL ids sort first. Duplicate
+    /// ids are left to `insert_or` to reject.
+    fn insert(&mut self, row: &Row) -> Result<usize, Box<dyn Error>> {
+        self.check_writable()?;
+        if let Some(max_rows) = self.max_rows
+            && self.row_count >= max_rows
+        {
+            return Err(MysqliteError::TableFull(max_rows).into());
+        }
+
+        let index = self.insertion_point(row.key())?;
+        for i in (index..self.row_count).rev() {
+            self.move_row(i, i + 1)?;
+        }
+
+        let (page_num, byte_offset) = self.row_location(index);
+        let row_size = self.schema.row_size();
+        row.serialize(
+            &self.schema,
+            &mut self.pager.get_page(page_num)?[byte_offset..byte_offset + row_size],
+        );
+
+        self.row_count += 1;
+
+        Ok(index)
+    }
+
+    /// Gathers `.stats` from the table and pager without reading any rows.
+    fn stats(&self) -> io::Result<Stats> {
+        let pages = self.row_count.div_ceil(self.schema.rows_per_page());
+        let fill = match pages {
+            0 => 0.0,
+            pages => {
+                (self.row_count * self.schema.row_size()) as f64
+                    / (pages * self.schema.page_size) as f64
+            }
+        };
+        Ok(Stats {
+            rows: self.row_count,
+            pages_on_disk: self.pager.file_page_count()?,
+            pages_cached: self.pager.pages.len(),
+            pages_dirty: self.pager.dirty.len(),
+            page_size: self.schema.page_size,
+            fill,
+        })
+    }
+
+    /// Reads every row and describes each problem found: text that isn't
+    /// valid UTF-8, ids of 0 and ids that appear more than once.
+    fn check(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut problems = vec![];
+        let mut previous_key = None;
+
+        for (index, row) in self.iter_rows().enumerate() {
+            let row = row?;
+            for (column, bytes) in [
+                (ColumnSelector::Username, &row.username[..]),
+                (ColumnSelector::Email, &row.email[..]),
+            ] {
+                if !row.is_null(column) && Row::try_bytes_to_str(bytes).is_none() {
+                    problems.push(format!("row {index}: {column} is not valid UTF-8"));
+                }
+            }
+
+            // Rows are sorted by id, so duplicates are always neighbours.
+            match row.key() {
+                Some(0) => problems.push(format!("row {index}: id is 0")),
+                Some(id) if previous_key == Some(id) => {
+                    problems.push(format!("row {index}: duplicate id {id}"));
+                }
+                _ => {}
+            }
+            previous_key = row.key();
+        }
+
+        Ok(problems)
+    }
+
+    /// Inserts `row` unless another row has its id, in which case `action`
+    /// decides what happens. NULL ids never clash. Returns the index of the
+    /// row written, or `None` if nothing was.
+    fn insert_or(
+        &mut self,
+        row: &Row,
+        action: &ConflictAction,
+    ) -> Result<Option<usize>, Box<dyn Error>> {
+        self.check_writable()?;
+
+        let existing = match row.key() {
+            Some(id) => self.find(id)?.map(|index| (id, index)),
+            None => None,
+        };
+        match (existing, action) {
+            (None, _) => Ok(Some(self.insert(row)?)),
+            (Some((id, _)), ConflictAction::Fail) => Err(MysqliteError::DuplicateId(id).into()),
+            (Some(_), ConflictAction::Ignore) => Ok(None),
+            (Some((_, index)), ConflictAction::Update { assignments }) => {
+                self.update_row(index, assignments)?;
+                Ok(Some(index))
+            }
+            (Some((_, index)), ConflictAction::Replace) => {
+                // The id is unchanged, so the row stays where it sorts.
+                let (page_num, byte_offset) = self.row_location(index);
+                let row_size = self.schema.row_size();
+                row.serialize(
+                    &self.schema,
+                    &mut self.pager.get_page(page_num)?[byte_offset..byte_offset + row_size],
+                );
+                Ok(Some(index))
+            }
+        }
+    }
+
+    /// Returns one more than the largest id, which is the last row's since
+    /// rows are kept sorted, or 1 if there are no ids yet.
+    fn next_id(&mut self) -> Result<u32, Box<dyn Error>> {
+        let last = match self.row_count {
+            0 => None,
+            count => self.key(count - 1)?,
+        };
+
+        match last {
+            Some(id) => Ok(id.checked_add(1).ok_or(MysqliteError::Overflow)?),
+            None => Ok(1),
+        }
+    }
+
+    /// Returns the index of the first row whose id sorts after `key`.
+    fn insertion_point(&mut self, key: Option<u32>) -> Result<usize, Box<dyn Error>> {
+        self.partition_point(|row_key| row_key <= key)
+    }
+
+    /// Returns the id of row `index`, where `None` is a NULL id. Only the
+    /// id is read, and the page isn't cached, so searching doesn't load
+    /// every page it probes.
+    fn key(&mut self, index: usize) -> Result<Option<u32>, Box<dyn Error>> {
+        let (page_num, byte_offset) = self.row_location(index);
+        let mut bytes = [0; Row::NULLS_SIZE + Row::ID_SIZE];
+        self.pager.peek(page_num, byte_offset, &mut bytes)?;
+
+        let mut id = [0; Row::ID_SIZE];
+        id.copy_from_slice(&bytes[Row::ID_OFFSET..]);
+        let is_null = bytes[Row::NULLS_OFFSET] & ColumnSelector::Id.null_bit() != 0;
+        Ok((!is_null).then_some(u32::from_le_bytes(id)))
+    }
+
+    /// Binary searches the sorted rows for the first index whose key fails
+    /// `pred`, which must hold for a prefix of the table.
+    fn partition_point<P>(&mut self, pred: P) -> Result<usize, Box<dyn Error>>
+    where
+        P: Fn(Option<u32>) -> bool,
+    {
+        let (mut low, mut high) = (0, self.row_count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if pred(self.key(mid)?) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low)
+    }
+
+    /// Returns the index of the first row with `id`.
+    fn find(&mut self, id: u32) -> Result<Option<usize>, Box<dyn Error>> {
+        let index = self.partition_point(|key| key < Some(id))?;
+        if index < self.row_count && self.key(index)? == Some(id) {
+            Ok(Some(index))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the stored bytes of the row with `id`, if there is one.
+    fn raw_row(&mut self, id: u32) -> Result<Option<RawRow>, Box<dyn Error>> {
+        let Some(index) = self.find(id)? else {
+            return Ok(None);
+        };
+
+        let (page_num, byte_offset) = self.row_location(index);
+        let row_size = self.schema.row_size();
+        let bytes =
+            self.pager.get_page_readonly(page_num)?[byte_offset..byte_offset + row_size].to_vec();
+        Ok(Some(RawRow {
+            bytes,
+            schema: self.schema,
+        }))
+    }
+
+    /// Returns the range of row indices holding ids in `ids`, which may
+    /// repeat.
+    fn id_range(&mut self, ids: RangeInclusive<u32>) -> Result<Range<usize>, Box<dyn Error>> {
+        if ids.is_empty() {
+            return Ok(0..0);
+        }
+        let start = self.partition_point(|key| key < Some(*ids.start()))?;
+        let end = self.partition_point(|key| key <= Some(*ids.end()))?;
+        Ok(start..end)
+    }
+
+    /// Returns the rows `filter` can match: a binary-searched range for
+    /// `id = n` or `id between a and b`, otherwise the whole table.
+    fn candidate_rows(
+        &mut self,
+        filter: Option<&WhereClause>,
+    ) -> Result<Range<usize>, Box<dyn Error>> {
+        match filter.and_then(WhereClause::id_lookup) {
+            Some(ids) => self.id_range(ids),
+            None => Ok(0..self.row_count),
+        }
+    }
+
+    fn move_row(&mut self, from: usize, to: usize) -> Result<(), Box<dyn Error>> {
+        let row_size = self.schema.row_size();
+        let (page_num, byte_offset) = self.row_location(from);
+        let bytes =
+            self.pager.get_page_readonly(page_num)?[byte_offset..byte_offset + row_size].to_vec();
+
+        let (page_num, byte_offset) = self.row_location(to);
+        self.pager.get_page(page_num)?[byte_offset..byte_offset + row_size].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Removes the rows matching `filter`, moving later rows down over them,
+    /// and returns how many there were.
+    fn delete_where(&mut self, filter: &WhereClause) -> Result<usize, Box<dyn Error>> {
+        self.check_writable()?;
+
+        // Rows before the candidates stay where they are.
+        let candidates = self.candidate_rows(Some(filter))?;
+        let mut kept = candidates.start;
+        for i in candidates.start..self.row_count {
+            if candidates.contains(&i) && filter.matches(&self.deserialize_row(i)?) {
+                continue;
+            }
+            if i != kept {
+                self.move_row(i, kept)?;
+            }
+            kept += 1;
+        }
+
+        let deleted = self.row_count - kept;
+        self.row_count = kept;
+        Ok(deleted)
+    }
+
+    /// Applies `assignments` to each row matching `filter`, returning how
+    /// many there were.
+    fn update_where(
+        &mut self,
+        assignments: &[(ColumnSelector, Option<Value>)],
+        filter: Option<&WhereClause>,
+    ) -> Result<usize, Box<dyn Error>> {
+        self.check_writable()?;
+
+        let mut updated = 0;
+        for i in self.candidate_rows(filter)? {
+            if let Some(filter) = filter
+                && !filter.matches(&self.deserialize_row(i)?)
+            {
+                continue;
+            }
+            self.update_row(i, assignments)?;
+            updated += 1;
+        }
+
+        Ok(updated)
+    }
+
+    /// Overwrites the assigned fields of the row at `index`, leaving the
+    /// bytes of every other column untouched.
+    fn update_row(
+        &mut self,
+        index: usize,
+        assignments: &[(ColumnSelector, Option<Value>)],
+    ) -> Result<(), Box<dyn Error>> {
+        // Check every value before writing any, so a bad one leaves the row
+        // as it was.
+        let mut fields = vec![];
+        for (column, value) in assignments {
+            let bytes = match (column, value) {
+                (ColumnSelector::Id, _) => return Err("the id can't be updated".into()),
+                (_, None) => vec![],
+                (ColumnSelector::Age, Some(Value::Integer(age))) => {
+                    u16::try_from(*age)?.to_le_bytes().to_vec()
+                }
+                (column, Some(Value::Text(text))) if column.is_text() => text.as_bytes().to_vec(),
+                (column, Some(value)) => {
+                    return Err(format!("can't set {column} to {value}").into());
+                }
+            };
+            let (offset, size) = self.schema.field(*column);
+            if bytes.len() > size {
+                return Err("value is too long for column".into());
+            }
+            fields.push((*column, value.is_none(), offset, size, bytes));
+        }
+
+        let (page_num, byte_offset) = self.row_location(index);
+        let row_size = self.schema.row_size();
+        let row = &mut self.pager.get_page(page_num)?[byte_offset..byte_offset + row_size];
+        for (column, is_null, offset, size, bytes) in fields {
+            if is_null {
+                row[Row::NULLS_OFFSET] |= column.null_bit();
+            } else {
+                row[Row::NULLS_OFFSET] &= !column.null_bit();
+            }
+
+            let field = &mut row[offset..offset + size];
+            field.fill(0);
+            field[..bytes.len()].copy_from_slice(&bytes);
+        }
+
+        Ok(())
+    }
+
+    /// Counts the rows matching `filter`, reading none of them without one.
+    fn count_filtered(&mut self, filter: Option<&WhereClause>) -> Result<usize, Box<dyn Error>> {
+        let Some(filter) = filter else {
+            return Ok(self.row_count);
+        };
+
+        let mut count = 0;
+        for row in self.iter_rows_where(filter)? {
+            row?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn aggregate(
+        &mut self,
+        op: AggregateOp,
+        column: ColumnSelector,
+        filter: Option<&WhereClause>,
+    ) -> Result<Option<Value>, Box<dyn Error>> {
+        let mut accumulator = Accumulator::new(op);
+        for i in self.candidate_rows(filter)? {
+            let row = self.deserialize_row(i)?;
+            if filter.is_some_and(|filter| !filter.matches(&row)) {
+                continue;
+            }
+
+            if let Some(value) = row.value(column) {
+                accumulator.add(value)?;
+            }
+        }
+
+        Ok(accumulator.finish()?)
+    }
+
+    /// Prints the matching rows, or only the first occurrence of each
+    /// output tuple when `distinct` is set.
+    fn select<W>(
+        &mut self,
+        projection: &[ProjectionExpr],
+        filter: Option<&WhereClause>,
+        distinct: bool,
+        mode: OutputMode,
+        output: &mut W,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        W: io::Write,
+    {
+        let names = projection_names(projection);
+        let mut seen = HashSet::new();
+        let mut first = true;
+
+        self.scan_projected(projection, filter, |values| {
+            if distinct && !seen.insert(values.clone()) {
+                return Ok(());
+            }
+            mode.write_row(output, &names, &values, first)?;
+            first = false;
+            Ok(())
+        })
+    }
+
+    /// Calls `f` with the projected values of every row matching `filter`.
+    fn scan_projected<F>(
+        &mut self,
+        projection: &[ProjectionExpr],
+        filter: Option<&WhereClause>,
+        mut f: F,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(Vec<Option<String>>) -> Result<(), Box<dyn Error>>,
+    {
+        for i in self.candidate_rows(filter)? {
+            let row = self.deserialize_row(i)?;
+            if filter.is_some_and(|filter| !filter.matches(&row)) {
+                continue;
+            }
+
+            let values: Vec<_> = if projection.is_empty() {
+                ColumnSelector::ALL
+                    .iter()
+                    .map(|column| row.value(*column).map(|value| value.to_string()))
+                    .collect()
+            } else {
+                projection.iter().map(|expr| expr.evaluate(&row)).collect()
+            };
+            f(values)?;
+        }
+
+        Ok(())
+    }
+
+    /// Describes how `statement` would be executed, one step per line, with
+    /// nested steps indented below the step that runs them.
+    fn explain(&self, statement: &Statement) -> String {
+        let mut plan = String::new();
+        self.explain_into(statement, 0, &mut plan);
+        plan
+    }
+
+    fn explain_into(&self, statement: &Statement, depth: usize, plan: &mut String) {
+        let rows = match self.row_count {
+            1 => "1 row".to_string(),
+            count => format!("{count} rows"),
+        };
+        let scan = |filter: Option<&WhereClause>| match filter {
+            Some(filter) if filter.id_lookup().is_some() => {
+                format!("Binary search: rows ({rows}), filter: {filter}")
+            }
+            Some(filter) => format!("Table scan: rows ({rows}), filter: {filter}"),
+            None => format!("Table scan: rows ({rows})"),
+        };
+
+        let step = match statement {
+            Statement::Insert { auto_id: true, .. } => {
+                "Insert into rows with the next id".to_string()
+            }
+            Statement::Insert { row, .. } => format!("Insert into rows in id order: {row}"),
+            Statement::Select {
+                filter,
+                group_by,
+                distinct,
+                ..
+            } => {
+                let mut step = scan(filter.as_ref());
+                if let Some(group_by) = group_by {
+                    step.push_str(&format!(", group by: {group_by}"));
+                }
+                if *distinct {
+                    step.push_str(", distinct");
+                }
+                step
+            }
+            Statement::Compound { op, .. } => format!("Compound select: {op}"),
+            Statement::Count(None) => "Row count: rows".to_string(),
+            Statement::Count(filter) => format!("{}, count", scan(filter.as_ref())),
+            Statement::Aggregate { op, column, filter } => {
+                format!("{}, aggregate: {op}({column})", scan(filter.as_ref()))
+            }
+            Statement::Update {
+                assignments,
+                filter,
+            } => {
+                let columns: Vec<_> = assignments
+                    .iter()
+                    .map(|(column, _)| column.to_string())
+                    .collect();
+                format!("{}, update: {}", scan(filter.as_ref()), columns.join(", "))
+            }
+            Statement::DeleteAll => "Truncate rows".to_string(),
+            Statement::Delete(filter) => format!("{}, delete", scan(Some(filter))),
+            Statement::Explain(statement) => return self.explain_into(statement, depth, plan),
+        };
+        plan.push_str(&format!("{:indent$}{step}\n", "", indent = depth * 2));
+
+        let children = match statement {
+            Statement::Compound { left, right, .. } => vec![&**left, &**right],
+            Statement::Select {
+                filter: Some(filter),
+                ..
+            }
+            | Statement::Count(Some(filter))
+            | Statement::Aggregate {
+                filter: Some(filter),
+                ..
+            }
+            | Statement::Update {
+                filter: Some(filter),
+                ..
+            }
+            | Statement::Delete(filter) => filter.subqueries(),
+            _ => vec![],
+        };
+        for child in children {
+            self.explain_into(child, depth + 1, plan);
+        }
+    }
+
+    /// Runs any subqueries in `filter`, replacing them with their results so
+    /// the clause can be evaluated row by row.
+    fn bind_filter(
+        &mut self,
+        filter: Option<&WhereClause>,
+    ) -> Result<Option<WhereClause>, Box<dyn Error>> {
+        filter.map(|filter| self.bind(filter)).transpose()
+    }
+
+    fn bind(&mut self, clause: &WhereClause) -> Result<WhereClause, Box<dyn Error>> {
+        Ok(match clause {
+            WhereClause::InSubquery { column, query } => {
+                let mut values = HashSet::new();
+                let mut has_null = false;
+                for row in self.collect_rows(query)?.rows {
+                    match row.into_iter().next().flatten() {
+                        Some(value) => {
+                            values.insert(value);
+                        }
+                        None => has_null = true,
+                    }
+                }
+                WhereClause::In {
+                    column: *column,
+                    values,
+                    has_null,
+                }
+            }
+            WhereClause::Like { column, pattern } => WhereClause::Like {
+                column: *column,
+                pattern: pattern.clone(),
+            },
+            WhereClause::Compare { column, op, value } => WhereClause::Compare {
+                column: *column,
+                op: *op,
+                value: value.clone(),
+            },
+            WhereClause::Between { column, low, high } => WhereClause::Between {
+                column: *column,
+                low: low.clone(),
+                high: high.clone(),
+            },
+            WhereClause::In {
+                column,
+                values,
+                has_null,
+            } => WhereClause::In {
+                column: *column,
+                values: values.clone(),
+                has_null: *has_null,
+            },
+            WhereClause::IsNull(column) => WhereClause::IsNull(*column),
+            WhereClause::IsNotNull(column) => WhereClause::IsNotNull(*column),
+            WhereClause::Not(clause) => WhereClause::Not(Box::new(self.bind(clause)?)),
+            WhereClause::And(lhs, rhs) => {
+                WhereClause::And(Box::new(self.bind(lhs)?), Box::new(self.bind(rhs)?))
+            }
+            WhereClause::Or(lhs, rhs) => {
+                WhereClause::Or(Box::new(self.bind(lhs)?), Box::new(self.bind(rhs)?))
+            }
+        })
+    }
+
+    /// Runs a select or compound select and returns its column names and
+    /// result rows.
+    fn collect_rows(&mut self, statement: &Statement) -> Result<ResultSet, Box<dyn Error>> {
+        match statement {
+            // Grouped output already has one tuple per key, so `distinct`
+            // has nothing left to remove there.
+            Statement::Select {
+                projection,
+                filter,
+                group_by: Some(group_by),
+                ..
+            } => {
+                let filter = self.bind_filter(filter.as_ref())?;
+                Ok(ResultSet {
+                    names: projection.iter().map(ToString::to_string).collect(),
+                    rows: self.select_grouped(projection, filter.as_ref(), *group_by)?,
+                })
+            }
+            Statement::Select {
+                projection,
+                filter,
+                group_by: None,
+                distinct,
+            } => {
+                let filter = self.bind_filter(filter.as_ref())?;
+                let mut rows = vec![];
+                self.scan_projected(projection, filter.as_ref(), |values| {
+                    rows.push(values);
+                    Ok(())
+                })?;
+                if *distinct {
+                    dedup_rows(&mut rows);
+                }
+                Ok(ResultSet {
+                    names: projection_names(projection),
+                    rows,
+                })
+            }
+            Statement::Compound { op, left, right } => {
+                let mut result = self.collect_rows(left)?;
+                let right_rows = self.collect_rows(right)?.rows;
+                match op {
+                    CompoundOp::Union | CompoundOp::UnionAll => result.rows.extend(right_rows),
+                    CompoundOp::Intersect | CompoundOp::Except => {
+                        let keep = matches!(op, CompoundOp::Intersect);
+                        let right_rows: HashSet<_> = right_rows.into_iter().collect();
+                        result.rows.retain(|row| right_rows.contains(row) == keep);
+                    }
+                }
+                if !matches!(op, CompoundOp::UnionAll) {
+                    dedup_rows(&mut result.rows);
+                }
+                Ok(result)
+            }
+            _ => Err("statement can't be part of a compound select".into()),
+        }
+    }
+
+    /// Returns one tuple per distinct value of `group_by`, ordered by that
+    /// value, evaluating any aggregates in `projection` over the group.
+    fn select_grouped(
+        &mut self,
+        projection: &[ProjectionExpr],
+        filter: Option<&WhereClause>,
+        group_by: ColumnSelector,
+    ) -> Result<Vec<Vec<Option<String>>>, Box<dyn Error>> {
+        let new_accumulators = || -> Vec<_> {
+            projection
+                .iter()
+                .map(|expr| match expr {
+                    ProjectionExpr::Aggregate(op, _) => Accumulator::new(*op),
+                    _ => Accumulator::new(AggregateOp::Count),
+                })
+                .collect()
+        };
+
+        let mut groups = BTreeMap::new();
+        for i in self.candidate_rows(filter)? {
+            let row = self.deserialize_row(i)?;
+            if filter.is_some_and(|filter| !filter.matches(&row)) {
+                continue;
+            }
+
+            let accumulators = groups
+                .entry(row.value(group_by))
+                .or_insert_with(new_accumulators);
+            for (expr, accumulator) in projection.iter().zip(accumulators) {
+                let value = match expr {
+                    ProjectionExpr::CountAll => Some(Value::Integer(1)),
+                    ProjectionExpr::Aggregate(_, column) => row.value(*column),
+                    _ => None,
+                };
+                if let Some(value) = value {
+                    accumulator.add(value)?;
+                }
+            }
+        }
+
+        let mut rows = vec![];
+        for (key, accumulators) in groups {
+            let mut values = vec![];
+            for (expr, accumulator) in projection.iter().zip(accumulators) {
+                let value = if expr.is_aggregate() {
+                    accumulator.finish()?
+                } else {
+                    key.clone()
+                };
+                values.push(value.map(|value| value.to_string()));
+            }
+            rows.push(values);
+        }
+
+        Ok(rows)
+    }
+
+    /// Returns every row in id order, reading pages as it reaches them.
+    fn iter_rows(&mut self) -> impl Iterator<Item = Result<Row, MysqliteError>> + '_ {
+        let row_count = self.row_count;
+        (0..row_count).map(|index| self.deserialize_row(index))
+    }
+
+    /// Returns the rows matching `filter` in id order. An id lookup only
+    /// reads the pages its binary search visits and stops after the last id
+    /// in range instead of going on to the end of the table.
+    fn iter_rows_where<'a>(
+        &'a mut self,
+        filter: &'a WhereClause,
+    ) -> Result<impl Iterator<Item = Result<Row, MysqliteError>> + 'a, Box<dyn Error>> {
+        let rows = self.candidate_rows(Some(filter))?;
+        Ok(rows.filter_map(|index| match self.deserialize_row(index) {
+            Ok(row) if !filter.matches(&row) => None,
+            result => Some(result),
+        }))
+    }
+
+    fn deserialize_row(&mut self, index: usize) -> Result<Row, MysqliteError> {
+        let (page_num, byte_offset) = self.row_location(index);
+        let row_size = self.schema.row_size();
+        let page = &self.pager.get_page_readonly(page_num)?[byte_offset..byte_offset + row_size];
+        Ok(Row::deserialize(&self.schema, page))
+    }
+}
+
+/// Persists whatever a missing `close` would otherwise lose, e.g. when `run`
+/// bails out on an error. A table that was closed explicitly is left alone,
+/// and failures can only be reported, not returned.
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(err) = self.close() {
+            eprintln!("Error: failed to close database: {err}");
+        }
+    }
+}
+
+/// Parses a statement for a table with `schema`, which limits how long its
+/// text can be.
+fn prepare_statement(input_buffer: &str, schema: &Schema) -> Result<Statement, PrepareResult> {
+    let (keyword, stripped) = input_buffer
+        .split_once(char::is_whitespace)
+        .unwrap_or((input_buffer, ""));
+
+    match keyword.to_ascii_lowercase().as_str() {
+        "insert" => prepare_insert(stripped, schema),
+        "update" => prepare_update(stripped, schema),
+        "delete" => prepare_delete(stripped),
+        "truncate" => match stripped.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["table", "rows"] | ["rows"] => Ok(Statement::DeleteAll),
+            _ => Err(PrepareResult::SyntaxError),
+        },
+        "select" => prepare_compound(stripped),
+        "explain" => match prepare_statement(stripped.trim_start(), schema)? {
+            Statement::Explain(_) => Err(PrepareResult::SyntaxError),
+            statement => Ok(Statement::Explain(Box::new(statement))),
+        },
+        _ => Err(PrepareResult::UnrecognizedStatement),
+    }
+}
+
+/// Parses a select, possibly combined with further selects by `union`,
+/// `intersect` or `except`. Compound operators share one precedence level and are
+/// left-associative, so the last one splits first.
+fn prepare_compound(s: &str) -> Result<Statement, PrepareResult> {
+    // A bare `select` has an empty body, so the keyword may start `s`.
+    let s = &format!(" {s}");
+    let split = [" union ", " intersect ", " except "]
+        .into_iter()
+        .filter_map(|keyword| top_level_matches(s, keyword).last().map(|at| (at, keyword)))
+        .max();
+    let Some((at, keyword)) = split else {
+        return prepare_select(s);
+    };
+    let (left, right) = (&s[..at], &s[at + keyword.len()..]);
+
+    let (op, right) = match (keyword, strip_keyword(right.trim_start(), "all ")) {
+        (" union ", Some(right)) => (CompoundOp::UnionAll, right),
+        (" union ", None) => (CompoundOp::Union, right),
+        (" intersect ", _) => (CompoundOp::Intersect, right),
+        _ => (CompoundOp::Except, right),
+    };
+    let right = strip_keyword(right.trim_start(), "select").ok_or(PrepareResult::SyntaxError)?;
+
+    let left = prepare_compound(left)?;
+    let right = prepare_select(right)?;
+    match (left.column_count(), right.column_count()) {
+        (Some(left_count), Some(right_count)) if left_count == right_count => {}
+        _ => return Err(PrepareResult::SyntaxError),
+    }
+
+    Ok(Statement::Compound {
+        op,
+        left: Box::new(left),
+        right: Box::new(right),
+    })
+}
+
+fn prepare_select(s: &str) -> Result<Statement, PrepareResult> {
+    let s = s.trim();
+    // Subqueries in the where clause have their own keywords, so only look
+    // outside of parentheses.
+    let (s, group_by) = match top_level_matches(s, "group by ").next() {
+        Some(at) => (
+            s[..at].trim(),
+            Some(s[at + "group by ".len()..].trim().parse()?),
+        ),
+        None => (s, None),
+    };
+
+    let (projection, filter) = match strip_keyword(s, "where ") {
+        Some(clause) => ("", Some(clause)),
+        None => match top_level_matches(s, " where ").next() {
+            Some(at) => (&s[..at], Some(&s[at + " where ".len()..])),
+            None => (s, None),
+        },
+    };
+
+    let (projection, distinct) = match strip_keyword(projection, "distinct") {
+        Some(rest) if rest.is_empty() || rest.starts_with(' ') => (rest.trim(), true),
+        _ => (projection, false),
+    };
+
+    let filter = filter.map(str::parse).transpose()?;
+    let projection: Vec<ProjectionExpr> = if projection.is_empty() || projection == "*" {
+        vec![]
+    } else {
+        // Items are separated by commas, whitespace, or both.
+        split_top_level(projection, |c| c == ',')
+            .into_iter()
+            .flat_map(|item| {
+                let words: Vec<_> = split_top_level(item, char::is_whitespace)
+                    .into_iter()
+                    .filter(|word| !word.is_empty())
+                    .collect();
+                if words.is_empty() { vec![item] } else { words }
+            })
+            .map(str::parse)
+            .collect::<Result<_, _>>()?
+    };
+
+    if let Some(group_by) = group_by {
+        // Outside of aggregates, only the grouped column has a single value
+        // per group.
+        let valid = !projection.is_empty()
+            && projection.iter().all(|expr| match expr {
+                ProjectionExpr::Column(column) => *column == group_by,
+                expr => expr.is_aggregate(),
+            });
+        if !valid {
+            return Err(PrepareResult::SyntaxError);
+        }
+    } else {
+        match projection.as_slice() {
+            [ProjectionExpr::CountAll] => return Ok(Statement::Count(filter)),
+            [ProjectionExpr::Aggregate(op, column)] => {
+                return Ok(Statement::Aggregate {
+                    op: *op,
+                    column: *column,
+                    filter,
+                });
+            }
+            projection if projection.iter().any(ProjectionExpr::is_aggregate) => {
+                return Err(PrepareResult::SyntaxError);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Statement::Select {
+        projection,
+        filter,
+        group_by,
+        distinct,
+    })
+}
+
+fn prepare_insert(s: &str, schema: &Schema) -> Result<Statement, PrepareResult> {
+    let mut s = s.trim_start();
+    let mut on_conflict = None;
+    // `or` could also be a username, so it only starts a conflict clause
+    // when followed by an action and a row.
+    let mut words = s.splitn(3, char::is_whitespace);
+    if let (Some(or), Some(action), Some(rest)) = (words.next(), words.next(), words.next())
+        && or.eq_ignore_ascii_case("or")
+    {
+        on_conflict = match action.to_ascii_lowercase().as_str() {
+            "fail" => Some(ConflictAction::Fail),
+            "ignore" => Some(ConflictAction::Ignore),
+            "replace" => Some(ConflictAction::Replace),
+            _ => None,
+        };
+        if on_conflict.is_some() {
+            s = rest;
+        }
+    }
+
+    let mut words: Vec<_> = s.split_whitespace().collect();
+    let returning = match words.as_slice() {
+        [.., keyword, "*"] if keyword.eq_ignore_ascii_case("returning") => {
+            words.truncate(words.len() - 2);
+            true
+        }
+        _ => false,
+    };
+    let clause = words.windows(2).position(|pair| {
+        pair[0].eq_ignore_ascii_case("on") && pair[1].eq_ignore_ascii_case("conflict")
+    });
+    if let Some(index) = clause
+        && index + 2 < words.len()
+    {
+        if on_conflict.is_some() {
+            return Err(PrepareResult::SyntaxError);
+        }
+        on_conflict = Some(prepare_conflict_clause(&words[index + 2..], schema)?);
+        words.truncate(index);
+    }
+    let row_text = words.join(" ");
+    s = &row_text;
+
+    let word_count = s.split_whitespace().count();
+    let first = s.split_whitespace().next().unwrap_or_default();
+    let into = first.eq_ignore_ascii_case("into");
+    // Without an id there are only the username and email, and the table
+    // picks the id when the row is inserted. A number there is an id, so
+    // the row is missing its email instead.
+    let numeric = first.starts_with('(') || first.parse::<i64>().is_ok();
+    let (row, auto_id) = if into && word_count > 2 {
+        prepare_insert_into(s)?
+    } else if word_count == 2 && !numeric {
+        (Row::from_fields(Some(0), s)?, true)
+    } else {
+        (Row::from_str(s)?, false)
+    };
+    schema.check(&row)?;
+    Ok(Statement::Insert {
+        row: Box::new(row),
+        auto_id,
+        on_conflict,
+        returning,
+    })
+}
+
+/// Parses `into rows [(<columns>)] values (<values>)`. Columns left out
+/// are NULL, except the id, which the table picks. Without a column list,
+/// the values are the id, username, email and optionally the age, in that
+/// order. Returns the row and whether the id was left out.
+fn prepare_insert_into(s: &str) -> Result<(Row, bool), PrepareResult> {
+    let mut tokens = Tokens::new(s);
+    let mut keyword = |expected: &str| {
+        tokens
+            .next_word()
+            .is_some_and(|word| word.eq_ignore_ascii_case(expected))
+    };
+    if !keyword("into") || !keyword("rows") {
+        return Err(PrepareResult::SyntaxError);
+    }
+    let columns = match tokens.peek() {
+        Some('(') => Some(
+            tokens
+                .next_parenthesized()
+                .ok_or(PrepareResult::SyntaxError)?,
+        ),
+        _ => None,
+    };
+    if !tokens
+        .next_word()
+        .is_some_and(|word| word.eq_ignore_ascii_case("values"))
+    {
+        return Err(PrepareResult::SyntaxError);
+    }
+    let values = tokens
+        .next_parenthesized()
+        .ok_or(PrepareResult::SyntaxError)?;
+    if !tokens.rest().trim().is_empty() {
+        return Err(PrepareResult::SyntaxError);
+    }
+
+    let values = split_top_level(values, |c| c == ',');
+    let columns: Vec<ColumnSelector> = match columns {
+        Some(columns) => columns
+            .split(',')
+            .map(|column| column.trim().parse())
+            .collect::<Result<_, _>>()?,
+        None if (3..=ColumnSelector::ALL.len()).contains(&values.len()) => {
+            ColumnSelector::ALL[..values.len()].to_vec()
+        }
+        None => return Err(PrepareResult::SyntaxError),
+    };
+    if columns.len() != values.len() {
+        return Err(PrepareResult::SyntaxError);
+    }
+
+    let mut fields = [None; ColumnSelector::ALL.len()];
+    let mut given = [false; ColumnSelector::ALL.len()];
+    for (column, value) in columns.into_iter().zip(values) {
+        let index = ColumnSelector::ALL
+            .iter()
+            .position(|&c| c == column)
+            .expect("every column is in ALL");
+        if given[index] {
+            return Err(PrepareResult::SyntaxError);
+        }
+        given[index] = true;
+
+        // Text is a single word or `'quoted'` string, as in `set`, while
+        // numbers may be expressions.
+        let value = if column.is_text() {
+            let mut tokens = Tokens::new(value);
+            let word = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+            if !tokens.rest().trim().is_empty() {
+                return Err(PrepareResult::SyntaxError);
+            }
+            word
+        } else {
+            value
+        };
+        fields[index] = nullable(value).map(|value| {
+            value
+                .strip_prefix('\'')
+                .and_then(|v| v.strip_suffix('\''))
+                .unwrap_or(value)
+        });
+    }
+
+    let [id, username, email, age] = fields;
+    let auto_id = !given[0];
+    let id = if auto_id { Some(0) } else { parse_number(id)? };
+    let row = Row::from_values(id, username, email, parse_number(age)?);
+    Ok((row, auto_id))
+}
+
+/// Parses a nullable integer column value, which may be an arithmetic
+/// expression.
+fn parse_number<T: TryFrom<i64>>(value: Option<&str>) -> Result<Option<T>, PrepareResult> {
+    value
+        .map(|value| {
+            let mut tokens = Tokens::new(value);
+            let value = match eval_expr(&mut tokens) {
+                Ok(value) if tokens.rest().trim().is_empty() => value,
+                Err(PrepareResult::DivisionByZero) => return Err(PrepareResult::DivisionByZero),
+                _ => return Err(PrepareResult::TypeMismatch),
+            };
+            T::try_from(value).map_err(|_| PrepareResult::TypeMismatch)
+        })
+        .transpose()
+}
+
+/// Parses what follows `on conflict`: `do nothing`, or `do update set
+/// <column> = <value>`.
+fn prepare_conflict_clause(
+    words: &[&str],
+    schema: &Schema,
+) -> Result<ConflictAction, PrepareResult> {
+    match words {
+        [keyword, nothing]
+            if keyword.eq_ignore_ascii_case("do") && nothing.eq_ignore_ascii_case("nothing") =>
+        {
+            Ok(ConflictAction::Ignore)
+        }
+        [keyword, update, set @ ..]
+            if keyword.eq_ignore_ascii_case("do") && update.eq_ignore_ascii_case("update") =>
+        {
+            let assignments = prepare_set(&set.join(" "), schema)?;
+            Ok(ConflictAction::Update { assignments })
+        }
+        _ => Err(PrepareResult::SyntaxError),
+    }
+}
+
+/// Parses `delete from [rows] [where <clause>]`.
+fn prepare_delete(s: &str) -> Result<Statement, PrepareResult> {
+    let (target, filter) = match top_level_matches(s, " where ").next() {
+        Some(at) => (&s[..at], Some(&s[at + " where ".len()..])),
+        None => (s, None),
+    };
+    match target.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["from"] | ["from", "rows"] => {}
+        _ => return Err(PrepareResult::SyntaxError),
+    }
+
+    match filter {
+        Some(filter) => Ok(Statement::Delete(filter.parse()?)),
+        None => Ok(Statement::DeleteAll),
+    }
+}
+
+/// Parses `update rows set ... [where ...]`, or `update <id> set ...` for
+/// the row with that id.
+fn prepare_update(s: &str, schema: &Schema) -> Result<Statement, PrepareResult> {
+    let (target, rest) = s
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or(PrepareResult::SyntaxError)?;
+    let (set, filter) = match top_level_matches(rest, " where ").next() {
+        Some(at) => (&rest[..at], Some(rest[at + " where ".len()..].parse()?)),
+        None => (rest, None),
+    };
+
+    let filter = if target.eq_ignore_ascii_case("rows") {
+        filter
+    } else {
+        let (Ok(id), None) = (target.parse::<u32>(), &filter) else {
+            return Err(PrepareResult::SyntaxError);
+        };
+        Some(WhereClause::Compare {
+            column: ColumnSelector::Id,
+            op: CompareOp::Eq,
+            value: Value::Integer(id.into()),
+        })
+    };
+
+    Ok(Statement::Update {
+        assignments: prepare_set(set, schema)?,
+        filter,
+    })
+}
+
+/// Parses `set <column> = <value>, ...`, where an age may be an arithmetic
+/// expression. The id can't be set, since it decides where the row is
+/// stored.
+fn prepare_set(
+    s: &str,
+    schema: &Schema,
+) -> Result<Vec<(ColumnSelector, Option<Value>)>, PrepareResult> {
+    let list = match s.trim().split_once(char::is_whitespace) {
+        Some((keyword, list)) if keyword.eq_ignore_ascii_case("set") => list,
+        _ => return Err(PrepareResult::SyntaxError),
+    };
+
+    split_top_level(list, |c| c == ',')
+        .into_iter()
+        .map(|assignment| {
+            let (column, value) = assignment
+                .split_once('=')
+                .ok_or(PrepareResult::SyntaxError)?;
+            let column: ColumnSelector = column.trim().parse()?;
+            if column == ColumnSelector::Id {
+                return Err(PrepareResult::SyntaxError);
+            }
+
+            if column == ColumnSelector::Age {
+                let age: Option<u16> = parse_number(nullable(value.trim()))?;
+                return Ok((column, age.map(|age| Value::Integer(age.into()))));
+            }
+
+            let mut tokens = Tokens::new(value);
+            let value = tokens.next_word().ok_or(PrepareResult::SyntaxError)?;
+            if !tokens.rest().trim().is_empty() {
+                return Err(PrepareResult::SyntaxError);
+            }
+
+            let value = match nullable(value) {
+                Some(value) => {
+                    let literal = value
+                        .strip_prefix('\'')
+                        .and_then(|v| v.strip_suffix('\''))
+                        .unwrap_or(value);
+                    if schema
+                        .text_size(column)
+                        .is_some_and(|size| literal.len() > size)
+                    {
+                        return Err(PrepareResult::StringTooLong);
+                    }
+                    Some(literal_value(column, literal)?)
+                }
+                None => None,
+            };
+            Ok((column, value))
+        })
+        .collect()
+}
+
+impl Statement {
+    /// Returns the number of columns an ungrouped select produces, or `None`
+    /// for statements that can't be an operand of a compound select.
+    fn column_count(&self) -> Option<usize> {
+        match self {
+            Self::Select {
+                projection,
+                group_by: None,
+                ..
+            } if projection.is_empty() => Some(ColumnSelector::ALL.len()),
+            Self::Select {
+                projection,
+                group_by: None,
+                ..
+            } => Some(projection.len()),
+            Self::Compound { left, .. } => left.column_count(),
+            _ => None,
+        }
+    }
+}
+
+fn execute_statement<W>(
+    statement: &Statement,
+    table: &mut Table,
+    mode: OutputMode,
+    output: &mut W,
+) -> Result<ExecuteResult, Box<dyn Error>>
+where
+    W: io::Write,
+{
+    match statement {
+        Statement::Insert {
+            row,
+            auto_id,
+            on_conflict,
+            returning,
+        } => {
+            let written = if *auto_id {
+                // A fresh id can't clash with anything.
+                let mut row = row.clone();
+                row.id = table.next_id()?;
+                Some(table.insert(&row)?)
+            } else {
+                let action = on_conflict.as_ref().unwrap_or(&ConflictAction::Fail);
+                table.insert_or(row, action)?
+            };
+
+            if !*returning {
+                return Ok(ExecuteResult::RowsAffected(usize::from(written.is_some())));
+            }
+            let mut result = ResultSet {
+                names: projection_names(&[]),
+                rows: vec![],
+            };
+            if let Some(index) = written {
+                let row = table.deserialize_row(index)?;
+                result.rows.push(
+                    ColumnSelector::ALL
+                        .iter()
+                        .map(|column| row.value(*column).map(|value| value.to_string()))
+                        .collect(),
+                );
+            }
+            result.write(output, mode)?;
+            Ok(ExecuteResult::RowsReturned)
+        }
+        // Plain selects stream their rows unless column widths have to be
+        // measured first.
+        Statement::Select {
+            projection,
+            filter,
+            group_by: None,
+            distinct,
+        } if !matches!(mode, OutputMode::Column) => {
+            let filter = table.bind_filter(filter.as_ref())?;
+            table.select(projection, filter.as_ref(), *distinct, mode, output)?;
+            Ok(ExecuteResult::RowsReturned)
+        }
+        Statement::Select { .. } | Statement::Compound { .. } => {
+            table.collect_rows(statement)?.write(output, mode)?;
+            Ok(ExecuteResult::RowsReturned)
+        }
+        Statement::Count(filter) => {
+            let filter = table.bind_filter(filter.as_ref())?;
+            let count = table.count_filtered(filter.as_ref())?;
+            writeln!(output, "{count}")?;
+            Ok(ExecuteResult::RowsReturned)
+        }
+        Statement::Aggregate { op, column, filter } => {
+            let filter = table.bind_filter(filter.as_ref())?;
+            match table.aggregate(*op, *column, filter.as_ref())? {
+                Some(value) => writeln!(output, "{op}({column}): {value}")?,
+                None => writeln!(output, "{op}({column}): NULL")?,
+            }
+            Ok(ExecuteResult::RowsReturned)
+        }
+        Statement::Update {
+            assignments,
+            filter,
+        } => {
+            let filter = table.bind_filter(filter.as_ref())?;
+            let count = table.update_where(assignments, filter.as_ref())?;
+            Ok(ExecuteResult::RowsAffected(count))
+        }
+        Statement::DeleteAll => Ok(ExecuteResult::RowsAffected(table.clear()?)),
+        Statement::Delete(filter) => {
+            let filter = table.bind(filter)?;
+            Ok(ExecuteResult::RowsAffected(table.delete_where(&filter)?))
+        }
+        Statement::Explain(statement) => {
+            write!(output, "{}", table.explain(statement))?;
+            Ok(ExecuteResult::RowsReturned)
+        }
+    }
+}
+
+/// Inserts `count` synthetic rows with ids following the current row count.
+/// Inserts `count` rows with ids following the largest one, so that they
+/// never collide with rows already in the table. Stops early if the table
+/// fills up, and returns how many rows were inserted.
+fn generate_rows(table: &mut Table, count: usize) -> Result<usize, Box<dyn Error>> {
+    let first_id = table.next_id()?;
+    for offset in 0..count {
+        if table.is_full() {
+            return Ok(offset);
+        }
+        let id = first_id
+            .checked_add(u32::try_from(offset)?)
+            .ok_or(MysqliteError::Overflow)?;
+        let username = format!("user{id}");
+        let email = format!("person{id}@example.com");
+        let age = u16::try_from(id % 100)?;
+
+        let row = Row::new(&table.schema, id, &username, &email, age)
+            .map_err(|_| "generated row doesn't fit the table's columns")?;
+        table.insert(&row)?;
+    }
+
+    Ok(count)
+}
+
+const PROMPT: &str = "mysqlite> ";
+
+/// Marks a command echoed by `.echo on`, setting it apart from its output.
+const ECHO_PREFIX: &str = "db > ";
+
+fn print_prompt<W>(output: &mut W) -> io::Result<()>
+where
+    W: io::Write,
+{
+    write!(output, "{PROMPT}")?;
+    output.flush()
+}
+
+/// Reads the next line, or returns `None` at the end of the input.
+fn read_input<'a, R>(
+    input: &mut R,
+    input_buffer: &'a mut String,
+) -> Result<Option<&'a str>, io::Error>
+where
+    R: io::BufRead,
+{
+    input_buffer.clear();
+    // A signal can interrupt the read; whatever it got so far stays in the
+    // buffer and the read carries on with the rest of the line.
+    loop {
+        match input.read_line(input_buffer) {
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    if input_buffer.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(input_buffer.trim()))
+}
+
+/// Puts the terminal on stdin into raw mode, so keys arrive one at a time
+/// and unechoed, until dropped.
+struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut termios = std::mem::MaybeUninit::uninit();
+        // SAFETY: tcgetattr fills in the termios it's given when it succeeds.
+        let original = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios.assume_init()
+        };
+
+        // Output processing stays on, so "\n" still starts a new line.
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+        raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        // SAFETY: `raw` is a valid termios copied from the terminal's own.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        // SAFETY: `original` came from tcgetattr on the same terminal.
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
+
+/// Reads lines typed at a terminal, with arrow-key history and basic
+/// editing, and hands them out as buffered input so that `run` reads them
+/// the same way it reads a pipe.
+struct LineEditor<K, E> {
+    /// Where key presses come from.
+    keys: K,
+    /// Where the line being edited is drawn.
+    echo: E,
+    /// Whether to switch the terminal to raw mode while a line is edited.
+    raw_mode: bool,
+    history: Vec<String>,
+    /// The file each entered line is appended to, if any.
+    history_file: Option<PathBuf>,
+    /// The last line entered, with its newline, and how much of it `run`
+    /// has consumed.
+    line: Vec<u8>,
+    consumed: usize,
+}
+
+impl LineEditor<io::Stdin, io::Stdout> {
+    /// Edits lines on the terminal, with history kept in
+    /// `~/.mysqlite_history`.
+    fn terminal() -> Self {
+        let history_file = std::env::var_os("HOME")
+            .filter(|home| !home.is_empty())
+            .map(|home| Path::new(&home).join(".mysqlite_history"));
+        let mut history: Vec<String> = history_file
+            .as_ref()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        history.drain(..history.len().saturating_sub(Self::HISTORY_SIZE));
+
+        Self {
+            keys: io::stdin(),
+            echo: io::stdout(),
+            raw_mode: true,
+            history,
+            history_file,
+            line: vec![],
+            consumed: 0,
+        }
+    }
+}
+
+impl<K: io::Read, E: io::Write> LineEditor<K, E> {
+    /// Keeps at most this many lines of history, in memory and on load.
+    const HISTORY_SIZE: usize = 1000;
+
+    /// Reads the next key byte, or `None` at the end of the input.
+    fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            match self.keys.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Reads the rest of a character that starts with `first`, returning
+    /// `None` for bytes that aren't UTF-8.
+    fn next_char(&mut self, first: u8) -> io::Result<Option<char>> {
+        let len = match first {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.next_byte()? {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        Ok(std::str::from_utf8(&bytes)
+            .ok()
+            .and_then(|s| s.chars().next()))
+    }
+
+    /// Redraws the prompt and `buffer`, leaving the cursor at `cursor`.
+    fn redraw(&mut self, buffer: &[char], cursor: usize) -> io::Result<()> {
+        let text: String = buffer.iter().collect();
+        write!(self.echo, "\r{PROMPT}{text}\x1b[K")?;
+        if cursor < buffer.len() {
+            write!(self.echo, "\x1b[{}D", buffer.len() - cursor)?;
+        }
+        self.echo.flush()
+    }
+
+    /// Lets the user edit one line, returning `None` at the end of the
+    /// input. The prompt has already been printed.
+    fn edit_line(&mut self) -> io::Result<Option<String>> {
+        let _raw_mode = if self.raw_mode {
+            Some(RawMode::enable()?)
+        } else {
+            None
+        };
+
+        let mut buffer: Vec<char> = vec![];
+        let mut cursor = 0;
+        // Up and down step through the history; the line being typed is
+        // kept aside until down comes back to it.
+        let mut recalled = self.history.len();
+        let mut draft = vec![];
+
+        loop {
+            let Some(byte) = self.next_byte()? else {
+                if buffer.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            };
+
+            match byte {
+                b'\r' | b'\n' => break,
+                // Ctrl-D ends the input on an empty line, like EOF from a
+                // pipe, and deletes forward otherwise.
+                0x04 if buffer.is_empty() => {
+                    write!(self.echo, "\r\n")?;
+                    return Ok(None);
+                }
+                0x04 if cursor < buffer.len() => {
+                    buffer.remove(cursor);
+                }
+                // Ctrl-C abandons the line and starts over.
+                0x03 => {
+                    write!(self.echo, "^C\r\n")?;
+                    buffer.clear();
+                    cursor = 0;
+                    recalled = self.history.len();
+                }
+                0x7f | 0x08 if cursor > 0 => {
+                    cursor -= 1;
+                    buffer.remove(cursor);
+                }
+                0x01 => cursor = 0,
+                0x05 => cursor = buffer.len(),
+                0x15 => {
+                    buffer.drain(..cursor);
+                    cursor = 0;
+                }
+                0x1b => {
+                    let key = match self.next_byte()? {
+                        Some(b'[' | b'O') => self.next_byte()?,
+                        _ => None,
+                    };
+                    // `ESC [ 3 ~` and friends carry a number before the `~`.
+                    let key = match key {
+                        Some(digit @ b'0'..=b'9') => match self.next_byte()? {
+                            Some(b'~') => Some(digit),
+                            _ => None,
+                        },
+                        key => key,
+                    };
+                    match key {
+                        Some(b'A') if recalled > 0 => {
+                            if recalled == self.history.len() {
+                                draft = buffer.clone();
+                            }
+                            recalled -= 1;
+                            buffer = self.history[recalled].chars().collect();
+                            cursor = buffer.len();
+                        }
+                        Some(b'B') if recalled < self.history.len() => {
+                            recalled += 1;
+                            buffer = match self.history.get(recalled) {
+                                Some(line) => line.chars().collect(),
+                                None => std::mem::take(&mut draft),
+                            };
+                            cursor = buffer.len();
+                        }
+                        Some(b'C') if cursor < buffer.len() => cursor += 1,
+                        Some(b'D') if cursor > 0 => cursor -= 1,
+                        Some(b'H' | b'1' | b'7') => cursor = 0,
+                        Some(b'F' | b'4' | b'8') => cursor = buffer.len(),
+                        Some(b'3') if cursor < buffer.len() => {
+                            buffer.remove(cursor);
+                        }
+                        _ => {}
+                    }
+                }
+                byte if byte >= 0x20 => {
+                    if let Some(c) = self.next_char(byte)? {
+                        buffer.insert(cursor, c);
+                        cursor += 1;
+                    }
+                }
+                _ => {}
+            }
+            self.redraw(&buffer, cursor)?;
+        }
+
+        write!(self.echo, "\r\n")?;
+        self.echo.flush()?;
+        let line: String = buffer.into_iter().collect();
+        self.remember(&line);
+        Ok(Some(line))
+    }
+
+    /// Adds `line` to the history unless it's blank or repeats the last
+    /// entry.
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if self.history.len() > Self::HISTORY_SIZE {
+            self.history.remove(0);
+        }
+
+        // Losing history isn't worth interrupting the session over, so a
+        // file that can't be written is ignored.
+        if let Some(path) = &self.history_file {
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{line}"));
+        }
+    }
+}
+
+impl<K: io::Read, E: io::Write> io::Read for LineEditor<K, E> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = io::BufRead::fill_buf(self)?;
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        io::BufRead::consume(self, len);
+        Ok(len)
+    }
+}
+
+impl<K: io::Read, E: io::Write> io::BufRead for LineEditor<K, E> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.line.len() {
+            self.line = match self.edit_line()? {
+                Some(line) => format!("{line}\n").into_bytes(),
+                None => vec![],
+            };
+            self.consumed = 0;
+        }
+        Ok(&self.line[self.consumed..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.consumed += amount;
+    }
+}
+
+fn do_meta_command(command: &str) -> Result<RunControl, MetaCommandResult> {
+    let mut parts = command.split_whitespace();
+    let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+    let args: Vec<_> = parts.collect();
+
+    match (name.as_str(), args.as_slice()) {
+        (".exit", []) => Ok(RunControl::Exit),
+        (".version", []) => Ok(RunControl::Version),
+        (".gen", [count]) => count
+            .parse()
+            .map(RunControl::Generate)
+            .map_err(|_| MetaCommandResult::UnrecognizedCommand),
+        (".clear", []) => Ok(RunControl::Clear),
+        (".check", []) => Ok(RunControl::Check),
+        (".sync", [setting]) => match setting.to_ascii_lowercase().as_str() {
+            "on" => Ok(RunControl::Sync(SyncMode::Data)),
+            "full" => Ok(RunControl::Sync(SyncMode::Full)),
+            "off" => Ok(RunControl::Sync(SyncMode::None)),
+            _ => Err(MetaCommandResult::UnrecognizedCommand),
+        },
+        (".echo", [setting]) if setting.eq_ignore_ascii_case("on") => Ok(RunControl::Echo(true)),
+        (".echo", [setting]) if setting.eq_ignore_ascii_case("off") => Ok(RunControl::Echo(false)),
+        (".pagecount", []) => Ok(RunControl::PageCount),
+        (".stats", []) => Ok(RunControl::Stats),
+        (".pageinfo", [index]) => index
+            .parse()
+            .map(RunControl::PageInfo)
+            .map_err(|_| MetaCommandResult::UnrecognizedCommand),
+        (".get", [id]) => id
+            .parse()
+            .map(RunControl::Get)
+            .map_err(|_| MetaCommandResult::UnrecognizedCommand),
+        (".read", [path]) => Ok(RunControl::Read(PathBuf::from(path))),
+        (".output", ["stdout"]) => Ok(RunControl::Output(None)),
+        (".output", [path]) => Ok(RunControl::Output(Some(PathBuf::from(path)))),
+        (".mode", [mode]) => match mode.to_ascii_lowercase().as_str() {
+            "tuple" => Ok(RunControl::Mode(OutputMode::Tuple)),
+            "line" => Ok(RunControl::Mode(OutputMode::Line)),
+            "column" => Ok(RunControl::Mode(OutputMode::Column)),
+            _ => Err(MetaCommandResult::UnrecognizedCommand),
+        },
+        _ => Err(MetaCommandResult::UnrecognizedCommand),
+    }
+}
+
+/// Runs commands against `table` and closes it. With `prompt` off (batch
+/// mode) the output holds nothing but statement results and messages.
+fn run<R, W>(
+    input: &mut R,
+    output: &mut W,
+    mut table: Table,
+    prompt: bool,
+    init: Option<&Path>,
+) -> Result<(), Box<dyn Error>>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    match run_commands(input, output, &mut table, prompt, init) {
+        Ok(()) => {}
+        // The reader went away (e.g. `| head`), so there is nobody left to
+        // write to; still persist the table before shutting down.
+        Err(err) if is_broken_pipe(err.as_ref()) => {}
+        Err(err) => return Err(err),
+    }
+
+    table.close()?;
+    Ok(())
+}
+
+/// Returns whether `err` leaves the table or the output in a state the
+/// session can't go on from, as failed I/O does. Anything else is reported
+/// and the next command runs.
+fn is_fatal(err: &(dyn Error + 'static)) -> bool {
+    err.is::<io::Error>() || matches!(err.downcast_ref(), Some(MysqliteError::FlushFailed { .. }))
+}
+
+fn is_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+/// Runs the `init` script, if any, and then commands from `input` against
+/// `table` until `.exit` or the end of the input.
+fn run_commands<R, W>(
+    input: &mut R,
+    output: &mut W,
+    table: &mut Table,
+    prompt: bool,
+    init: Option<&Path>,
+) -> Result<(), Box<dyn Error>>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut session = Session::default();
+    if let Some(init) = init
+        && read_script(init, output, table, &mut session)?
+    {
+        return Ok(());
+    }
+
+    run_session(input, output, table, prompt, &mut session)?;
+    Ok(())
+}
+
+/// Runs the script at `path` the way `.read` does, returning whether it ran
+/// `.exit`.
+fn read_script<W: io::Write>(
+    path: &Path,
+    output: &mut W,
+    table: &mut Table,
+    session: &mut Session,
+) -> Result<bool, Box<dyn Error>> {
+    if session.depth == Session::MAX_READ_DEPTH {
+        writeln!(
+            output,
+            "Error: .read nested more than {} deep",
+            Session::MAX_READ_DEPTH
+        )?;
+        return Ok(false);
+    }
+
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            writeln!(output, "Cannot open '{}': {err}", path.display())?;
+            return Ok(false);
+        }
+    };
+
+    let mut script = io::BufReader::new(file);
+    session.depth += 1;
+    let result = run_session(&mut script, output, table, false, session);
+    session.depth -= 1;
+    // A failing script is reported without ending the session that read it.
+    match result {
+        Ok(exit) => Ok(exit),
+        Err(err) => {
+            writeln!(output, "Error: {err}")?;
+            Ok(false)
+        }
+    }
+}
+
+/// Runs commands from `input` until the end of the input, returning whether
+/// `.exit` stopped it first.
+fn run_session<R, W>(
+    input: &mut R,
+    output: &mut W,
+    table: &mut Table,
+    prompt: bool,
+    session: &mut Session,
+) -> Result<bool, Box<dyn Error>>
+where
+    R: io::BufRead,
+    W: io::Write,
+{
+    let mut input_buffer = String::new();
+
+    loop {
+        if prompt {
+            print_prompt(output)?;
+        }
+
+        let Some(command) = read_input(input, &mut input_buffer)? else {
+            return Ok(false);
+        };
+
+        if command.is_empty() {
+            continue;
+        }
+
+        if command.starts_with('.') {
+            match do_meta_command(command) {
+                Ok(RunControl::Exit) => return Ok(true),
+                Ok(RunControl::Version) => {
+                    writeln!(output, "mysqlite {}", env!("CARGO_PKG_VERSION"))?;
+                }
+                Ok(RunControl::Generate(count)) => {
+                    let start = Instant::now();
+                    match generate_rows(table, count) {
+                        Ok(generated) => {
+                            let elapsed = start.elapsed();
+                            writeln!(output, "Generated {generated} rows in {elapsed:.2?}")?;
+                            if generated < count {
+                                writeln!(
+                                    output,
+                                    "Table is full; {} rows were not generated.",
+                                    count - generated
+                                )?;
+                            }
+                        }
+                        Err(err) if is_fatal(err.as_ref()) => return Err(err),
+                        Err(err) => writeln!(output, "Error: {err}")?,
+                    }
+                }
+                Ok(RunControl::Echo(setting)) => session.echo = setting,
+                Ok(RunControl::Clear) => match table.clear() {
+                    Ok(_) => {}
+                    Err(err) => writeln!(output, "Error: {err}")?,
+                },
+                Ok(RunControl::Check) => {
+                    let problems = table.check()?;
+                    for problem in &problems {
+                        writeln!(output, "{problem}")?;
+                    }
+                    match problems.len() {
+                        0 => writeln!(output, "No problems found.")?,
+                        1 => writeln!(output, "1 problem found.")?,
+                        count => writeln!(output, "{count} problems found.")?,
+                    }
+                }
+                Ok(RunControl::Mode(setting)) => session.mode = setting,
+                Ok(RunControl::Sync(sync_mode)) => table.sync_mode = sync_mode,
+                Ok(RunControl::PageInfo(index)) => match table.pager.page_info(index)? {
+                    Some(info) => write!(output, "{info}")?,
+                    None => writeln!(output, "Page {index} does not exist.")?,
+                },
+                Ok(RunControl::PageCount) => {
+                    writeln!(output, "Pages in use: {}", table.pager.page_count())?;
+                    writeln!(output, "Pages in file: {}", table.pager.file_page_count()?)?;
+                }
+                Ok(RunControl::Stats) => write!(output, "{}", table.stats()?)?,
+                Ok(RunControl::Get(id)) => match table.raw_row(id)? {
+                    Some(row) => write!(output, "{row}")?,
+                    None => writeln!(output, "Row {id} does not exist.")?,
+                },
+                Ok(RunControl::Output(None)) => session.redirect = None,
+                Ok(RunControl::Output(Some(path))) => match std::fs::File::create(&path) {
+                    Ok(file) => session.redirect = Some(file),
+                    Err(err) => writeln!(output, "Cannot open '{}': {err}", path.display())?,
+                },
+                Ok(RunControl::Read(path)) => {
+                    if read_script(&path, output, table, session)? {
+                        return Ok(true);
+                    }
+                }
+                Err(MetaCommandResult::UnrecognizedCommand) => {
+                    writeln!(output, "Unrecognized command '{command}'")?;
+                }
+            }
+            continue;
+        }
+
+        if session.echo {
+            writeln!(output, "{ECHO_PREFIX}{command}")?;
+        }
+
+        let statement = match prepare_statement(command, &table.schema) {
+            Ok(statement) => statement,
             Err(err) => {
                 match err {
                     PrepareResult::SyntaxError => {
                         writeln!(output, "Syntax error. Could not parse statement.")?;
                     }
                     PrepareResult::StringTooLong => writeln!(output, "String is too long.")?,
+                    PrepareResult::DivisionByZero => writeln!(output, "Division by zero.")?,
+                    PrepareResult::TypeMismatch => writeln!(output, "Type mismatch.")?,
                     PrepareResult::UnrecognizedStatement => {
                         writeln!(output, "Unrecognized keyword at start of '{command}'.")?;
                     }
                 }
-                continue;
+                continue;
+            }
+        };
+
+        // Only results follow `.output`; the prompt and errors stay on the
+        // terminal.
+        let mut results: &mut dyn io::Write = match session.redirect.as_mut() {
+            Some(file) => file,
+            None => output,
+        };
+        let result = match execute_statement(&statement, table, session.mode, &mut results) {
+            Ok(result) => result,
+            Err(err) if is_fatal(err.as_ref()) => return Err(err),
+            Err(err) => {
+                writeln!(output, "Error: {err}")?;
+                continue;
+            }
+        };
+        match result {
+            ExecuteResult::RowsAffected(1) => writeln!(results, "Executed. (1 row)")?,
+            ExecuteResult::RowsAffected(count) => writeln!(results, "Executed. ({count} rows)")?,
+            ExecuteResult::RowsReturned => {}
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(version)]
+struct Args {
+    /// Database file, relative to $MYSQLITE_DIR if that is set
+    #[arg(default_value = "mysqlite.db")]
+    filename: PathBuf,
+
+    /// Run the commands in a script file instead of reading from stdin
+    #[arg(short, long, value_name = "SCRIPT")]
+    file: Option<PathBuf>,
+
+    /// Run the commands in a script file before reading any others
+    #[arg(long, value_name = "SCRIPT")]
+    init: Option<PathBuf>,
+
+    /// Write the database to a temp file and rename it into place on exit
+    #[arg(long)]
+    atomic_close: bool,
+
+    /// Fail rather than create the database file if it doesn't exist
+    #[arg(long)]
+    no_create: bool,
+
+    /// Open the database for reading only, refusing any modification
+    #[arg(long)]
+    readonly: bool,
+
+    /// Don't print prompts, leaving only results on stdout
+    #[arg(long, visible_alias = "no-prompt")]
+    batch: bool,
+
+    /// Permission bits (octal) used when creating a new database file
+    #[arg(long, value_parser = parse_mode)]
+    mode: Option<u32>,
+
+    /// Sync all file metadata on close, not just the data
+    #[arg(long, conflicts_with = "no_sync")]
+    full_sync: bool,
+
+    /// Don't sync the file on close, leaving it to the operating system
+    #[arg(long)]
+    no_sync: bool,
+
+    /// Keep at most this many unmodified pages cached
+    #[arg(long, value_name = "PAGES")]
+    cache_size: Option<usize>,
+
+    /// Refuse to insert rows once the table holds this many
+    #[arg(long, value_name = "ROWS")]
+    max_rows: Option<usize>,
+
+    /// Keep the table in memory only, without reading or writing the file
+    #[arg(long)]
+    in_memory: bool,
+
+    /// Page size in bytes, a power of two, for a new database file
+    #[arg(long, value_name = "BYTES", default_value_t = Schema::DEFAULT_PAGE_SIZE)]
+    page_size: usize,
+
+    /// Maximum username length in bytes, for a new database file
+    #[arg(long, value_name = "BYTES", default_value_t = Schema::DEFAULT_USERNAME_SIZE)]
+    username_size: usize,
+
+    /// Maximum email length in bytes, for a new database file
+    #[arg(long, value_name = "BYTES", default_value_t = Schema::DEFAULT_EMAIL_SIZE)]
+    email_size: usize,
+
+    /// Trace page reads and flushes to stderr
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+/// Resolves a relative database name under `dir`, the `MYSQLITE_DIR`
+/// directory. Absolute names are used as given.
+fn database_path(filename: &Path, dir: Option<&Path>) -> PathBuf {
+    match dir {
+        Some(dir) if filename.is_relative() => dir.join(filename),
+        _ => filename.to_path_buf(),
+    }
+}
+
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8).map_err(|err| format!("invalid octal mode: {err}"))
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    if let Err(err) = open_and_run(&args) {
+        eprintln!("Error: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+fn open_and_run(args: &Args) -> Result<(), Box<dyn Error>> {
+    let sync_mode = if args.full_sync {
+        SyncMode::Full
+    } else if args.no_sync {
+        SyncMode::None
+    } else {
+        SyncMode::Data
+    };
+    let mut options = TableOptions::default()
+        .mode(args.mode.unwrap_or(Pager::DEFAULT_MODE))
+        .create_if_missing(!args.no_create)
+        .read_only(args.readonly)
+        .atomic_close(args.atomic_close)
+        .sync_mode(sync_mode)
+        .in_memory(args.in_memory)
+        .schema(Schema {
+            page_size: args.page_size,
+            username_size: args.username_size,
+            email_size: args.email_size,
+        });
+    if let Some(pages) = args.cache_size {
+        options = options.cache_capacity(pages);
+    }
+    if let Some(rows) = args.max_rows {
+        options = options.max_rows(rows);
+    }
+    let dir = std::env::var_os("MYSQLITE_DIR").filter(|dir| !dir.is_empty());
+    let dir = dir.as_deref().map(Path::new);
+    if let Some(dir) = dir
+        && !args.no_create
+        && !args.readonly
+        && !args.in_memory
+    {
+        std::fs::create_dir_all(dir)?;
+    }
+    let path = database_path(&args.filename, dir);
+    let mut table = options.open(&path)?;
+    if args.verbose {
+        table.pager.log = Some(Box::new(io::stderr()));
+    }
+
+    let init = args.init.as_deref();
+    let mut stdout = io::stdout().lock();
+    match &args.file {
+        Some(script) => {
+            let mut script = io::BufReader::new(std::fs::File::open(script)?);
+            run(&mut script, &mut stdout, table, false, init)
+        }
+        // Line editing only makes sense when someone is typing; piped
+        // input is read exactly as it arrives.
+        None if !args.batch && io::stdin().is_terminal() => {
+            run(&mut LineEditor::terminal(), &mut stdout, table, true, init)
+        }
+        None => run(
+            &mut io::stdin().lock(),
+            &mut stdout,
+            table,
+            !args.batch,
+            init,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+    use std::rc::Rc;
+
+    use tempfile::TempDir;
+
+    use super::{
+        ColumnSelector, Error, LineEditor, MysqliteError, OutputMode, PrepareResult, Row, Schema,
+        SyncMode, Table, TableOptions, Tokens, database_path, eval_expr, generate_rows, io,
+        like_match, read_input, run,
+    };
+
+    #[test]
+    fn test_simple_insert_and_select() {
+        let scripts = ["insert 1 user1 person1@example.com", "select", ".exit"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\nmysqlite> (1 user1 person1@example.com NULL)\nmysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_auto_id() {
+        let scripts = [
+            "insert alice alice@example.com",
+            "insert bob bob@example.com",
+            "insert 10 carol carol@example.com",
+            "insert dave dave@example.com",
+            "insert 4294967295 max max@example.com",
+            "insert eve eve@example.com",
+            "insert 3 alice",
+            "insert (3) alice",
+            "select id, username",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Error: integer overflow\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (1 alice)\n\
+             (2 bob)\n\
+             (10 carol)\n\
+             (11 dave)\n\
+             (4294967295 max)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_row_serialize_round_trip() {
+        let mut row = Row::new(&Schema::default(), 7, "alice", "alice@example.com", 30)
+            .ok()
+            .unwrap();
+        row.set_null(super::ColumnSelector::Age);
+        let schema = Schema::default();
+        let mut buf = vec![0; schema.row_size()];
+        row.serialize(&schema, &mut buf);
+
+        assert_eq!(Row::deserialize(&schema, &buf), row);
+    }
+
+    #[test]
+    fn test_row_eq() {
+        let row = Row::new(&Schema::default(), 1, "a", "a@b.com", 20)
+            .ok()
+            .unwrap();
+        assert_eq!(
+            row,
+            Row::new(&Schema::default(), 1, "a", "a@b.com", 20)
+                .ok()
+                .unwrap()
+        );
+        assert_eq!(row.clone(), row);
+
+        let mut other = row.clone();
+        other.set_null(super::ColumnSelector::Age);
+        assert_ne!(other, row);
+    }
+
+    #[test]
+    fn test_insert_on_conflict() {
+        let scripts = [
+            "insert 1 alice alice@example.com 30",
+            "insert or fail 1 bob bob@example.com",
+            "insert or ignore 1 bob bob@example.com",
+            "insert or replace 1 carol carol@example.com",
+            "insert or fail 2 dave dave@example.com",
+            "insert or ignore NULL erin erin@example.com",
+            "insert or or@example.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Error: duplicate id 1\n\
+             mysqlite> Executed. (0 rows)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (NULL erin erin@example.com NULL)\n\
+             (1 carol carol@example.com NULL)\n\
+             (2 dave dave@example.com NULL)\n\
+             (3 or or@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_upsert() {
+        let upsert =
+            "insert 1 alice alice@example.com on conflict do update set email = new@example.com";
+        let scripts = [
+            upsert,
+            "select",
+            upsert,
+            "select",
+            "insert 1 bob bob@example.com on conflict do nothing",
+            "insert 1 bob bob@example.com on conflict do update set id = 3",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice alice@example.com NULL)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice new@example.com NULL)\n\
+             mysqlite> Executed. (0 rows)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (1 alice new@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_returning() {
+        let scripts = [
+            "insert 1 alice alice@example.com 30 returning *",
+            "select where id = 1",
+            "insert bob bob@example.com returning *",
+            "insert or ignore 1 carol carol@example.com returning *",
+            ".mode line",
+            "insert 1 dave dave@example.com on conflict do update set email = d@b.com returning *",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> (1 alice alice@example.com 30)\n\
+             mysqlite> (1 alice alice@example.com 30)\n\
+             mysqlite> (2 bob bob@example.com NULL)\n\
+             mysqlite> mysqlite> mysqlite> id = 1\n\
+             username = alice\n\
+             email = d@b.com\n\
+             age = 30\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_into_columns() {
+        let scripts = [
+            "insert into rows (id, email) values (1, 'a@b.com')",
+            "insert into rows (email, username, age) values (b@b.com, 'bob', 30)",
+            "insert into rows (id, username, email, age) values (7, carol, NULL, 40) returning *",
+            "insert into rows (id, email) values (1)",
+            "insert into rows (id, id) values (1, 2)",
+            "insert into rows (id, nickname) values (1, x)",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (7 carol NULL 40)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (1 NULL a@b.com NULL)\n\
+             (2 bob b@b.com 30)\n\
+             (7 carol NULL 40)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_into_quoted_values() {
+        let scripts = [
+            "insert into rows (id, username, email) values (2, 'a,b', 'c@d.com')",
+            "insert into rows values (3, 'x (y', 'z)@d.com', 7 * 6)",
+            "update rows set username = 'c, d' where id = 3",
+            "insert into rows values (4, a b, 'e@d.com')",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (2 a,b c@d.com NULL)\n\
+             (3 c, d z)@d.com 42)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_into_values() {
+        let scripts = [
+            "insert into rows values (1, 'alice', 'a@b.com')",
+            "insert or replace into rows values (1, 'alice', 'alice@example.com', 30)",
+            "insert or ignore into rows values (1, 'bob', 'b@b.com')",
+            "insert or fail into rows values (1, 'bob', 'b@b.com')",
+            "insert into rows values (2, 'bob', 'b@b.com') on conflict do nothing",
+            "insert into rows values (2, 'bob', 'b@b.com') on conflict do update set age = 40",
+            "insert into rows values (3, 'carol')",
+            "insert into rows values (3, 'carol', 'c@b.com', 50, 60)",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (0 rows)\n\
+             mysqlite> Error: duplicate id 1\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (1 alice alice@example.com 30)\n\
+             (2 bob b@b.com 40)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_username_max_length() {
+        let scripts = [
+            "insert 1 abcdefghijklmnopqrstuvwxyzabcdef a@b.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 abcdefghijklmnopqrstuvwxyzabcdef a@b.com NULL)\n\
+             mysqlite> "
+        );
+    }
+    #[test]
+    fn test_username_too_long() {
+        let scripts = [
+            "insert 1 abcdefghijklmnopqrstuvwxyzabcdefg a@b.com",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(output, "mysqlite> String is too long.\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_email_max_length() {
+        let n = 255;
+        let insert_str = &format!("insert 1 u {0:a<1$}", "", n);
+        let scripts = [insert_str, ".exit"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(output, "mysqlite> Executed. (1 row)\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_email_too_long() {
+        let n = 256;
+        let insert_str = &format!("insert 1 u {0:a<1$}", "", n);
+        let scripts = [insert_str, ".exit"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(output, "mysqlite> String is too long.\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_persistent_data() {
+        let scripts = ["insert 1 user1 person1@example.com", ".exit"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(output, "mysqlite> Executed. (1 row)\nmysqlite> ");
+
+        let scripts = ["select", ".exit"];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 user1 person1@example.com NULL)\nmysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_row_new() {
+        let row = Row::new(&Schema::default(), 1, "user1", "person1@example.com", 30)
+            .ok()
+            .unwrap();
+
+        assert_eq!(row.id, 1);
+        assert_eq!(row.username_str(), "user1");
+        assert_eq!(row.email_str(), "person1@example.com");
+        assert_eq!(row.age, 30);
+    }
+
+    #[test]
+    fn test_row_new_username_too_long() {
+        let schema = Schema::default();
+        let username = "a".repeat(schema.username_size + 1);
+        let result = Row::new(&schema, 1, &username, "a@b.com", 30);
+
+        assert!(matches!(result, Err(PrepareResult::StringTooLong)));
+    }
+
+    #[test]
+    fn test_like_match() {
+        assert!(like_match("alice", "al%"));
+        assert!(like_match("alice", "%ice"));
+        assert!(like_match("alice", "a%e"));
+        assert!(!like_match("bob", "al%"));
+        assert!(like_match("alice", "alice"));
+        assert!(!like_match("alice", "alic"));
+        assert!(like_match("alice", "al_ce"));
+        assert!(like_match("50%", "50\\%"));
+        assert!(!like_match("500", "50\\%"));
+        assert!(!like_match("Alice", "al%"));
+        assert!(like_match("", "%"));
+        assert!(!like_match("", "_"));
+        assert!(like_match("a\\", "a\\"));
+        assert!(like_match("abcbcd", "%bc%d"));
+        assert!(!like_match("abcbce", "%bc%d"));
+
+        // Backtracking into every `%` would take exponential time here.
+        let email = "a".repeat(255);
+        assert!(!like_match(&email, "%a%a%a%a%a%b"));
+        assert!(like_match(&email, "%a%a%a%a%a%a"));
+    }
+
+    #[test]
+    fn test_select_where_like() {
+        let scripts = [
+            "insert 1 alice alice@example.com",
+            "insert 2 alan alan@example.com",
+            "insert 3 bob bob@example.com",
+            "select where username like 'al%'",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice alice@example.com NULL)\n\
+             (2 alan alan@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_update_single_column() {
+        let scripts = [
+            "insert 1 user1 person1@example.com",
+            "insert 2 user2 person2@example.com",
+            "update 1 set email = new@example.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 user1 new@example.com NULL)\n\
+             (2 user2 person2@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_update_value_too_long() {
+        let scripts = [
+            "insert 1 user1 person1@example.com",
+            "update 1 set username = abcdefghijklmnopqrstuvwxyzabcdefg",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> String is too long.\n\
+             mysqlite> (1 user1 person1@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_update_where_keeps_other_columns() {
+        let scripts = [
+            "insert 1 alice alice@example.com 30",
+            "insert 2 bob bob@example.com 40",
+            "insert 3 carol carol@example.com 50",
+            "update rows set email = 'new@b.com', age = 31 where id = 1",
+            "update rows set username = null where age > 35",
+            "update rows set age = 70000",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (2 rows)\n\
+             mysqlite> Type mismatch.\n\
+             mysqlite> (1 alice new@b.com 31)\n\
+             (2 NULL bob@example.com 40)\n\
+             (3 NULL carol@example.com 50)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_null() {
+        let scripts = [
+            "insert 1 alice null",
+            "insert 2 NULL bob@example.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice NULL NULL)\n\
+             (2 NULL bob@example.com NULL)\n\
+             mysqlite> "
+        );
+
+        let scripts = ["update 1 set email = alice@example.com", "select", ".exit"];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice alice@example.com NULL)\n\
+             (2 NULL bob@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_empty() {
+        let scripts = [
+            "insert 1 alice ''",
+            "insert 2 bob null",
+            "select",
+            "select id where email = ''",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice '' NULL)\n\
+             (2 bob NULL NULL)\n\
+             mysqlite> (1)\n\
+             mysqlite> "
+        );
+
+        let mut table = TableOptions::default().open(&path).unwrap();
+        let row = table.iter_rows().next().unwrap().unwrap();
+        assert_eq!(row.email_str(), "");
+        assert!(!row.is_null(ColumnSelector::Email));
+    }
+
+    #[test]
+    fn test_rows_affected() {
+        let scripts = [
+            "insert 1 user1 person1@example.com",
+            "insert 2 user2 person2@example.com",
+            "update 3 set email = nobody@example.com",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (0 rows)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_keywords_case_insensitive() {
+        let scripts = ["Insert 1 User1 Person1@Example.com", "SELECT", ".EXIT"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 User1 Person1@Example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_extra_tokens() {
+        let scripts = [
+            "insert 1 u a@b.com garbage extra",
+            "insert 2 u a@b.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (2 u a@b.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_version() {
+        let scripts = [".version", ".exit"];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            format!(
+                "mysqlite> mysqlite {}\nmysqlite> ",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+    }
+
+    #[test]
+    fn test_eval_expr() {
+        let eval = |s| eval_expr(&mut Tokens::new(s));
+
+        assert!(matches!(eval("3 * 7 + 1"), Ok(22)));
+        assert!(matches!(eval("3 * (7 + 1)"), Ok(24)));
+        assert!(matches!(eval("10 - 4 - 3"), Ok(3)));
+        assert!(matches!(eval("-2 * -3"), Ok(6)));
+        assert!(matches!(eval("7 / 2"), Ok(3)));
+        assert!(matches!(eval("1 / 0"), Err(PrepareResult::DivisionByZero)));
+        assert!(matches!(eval("(1 + 2"), Err(PrepareResult::SyntaxError)));
+    }
+
+    #[test]
+    fn test_insert_expression_id() {
+        let scripts = [
+            "insert (3 * 7 + 1) alice a@b.com",
+            "insert (1 / (2 - 2)) bob b@b.com",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Division by zero.\n\
+             mysqlite> (22 alice a@b.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_expression_age() {
+        let scripts = [
+            "insert 1 alice a@b.com (3 * 7 + 1)",
+            "insert 2 bob b@b.com 10 - 4 / 2",
+            "insert into rows (username, email, age) values (carol, c@b.com, 2 * (5 + 5))",
+            "insert 4 dave d@b.com 1 / 0",
+            "insert 4 dave d@b.com 100 * 1000",
+            "update rows set age = (40 + 2) / 2 where id = 1",
+            "update rows set age = 0 - 1",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Division by zero.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Type mismatch.\n\
+             mysqlite> (1 alice a@b.com 21)\n\
+             (2 bob b@b.com 8)\n\
+             (3 carol c@b.com 20)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_upper_lower() {
+        let scripts = [
+            "insert 1 Alice alice@example.com",
+            "insert 2 bOB null",
+            "select upper(username)",
+            "select lower(username) where id like 2",
+            "select upper(email)",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (ALICE)\n\
+             (BOB)\n\
+             mysqlite> (bob)\n\
+             mysqlite> (ALICE@EXAMPLE.COM)\n\
+             (NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_partial_row_is_corrupt() {
+        let (_dir, path) = create_test_db_file();
+        let mut bytes = Schema::default().header().to_vec();
+        bytes.resize(
+            Schema::DEFAULT_PAGE_SIZE + Schema::default().row_size() + 1,
+            0,
+        );
+        std::fs::write(&path, bytes).unwrap();
+
+        let err = run_scripts(&[".exit"], &path).unwrap_err();
+        assert_eq!(err.to_string(), "corrupt database (partial row)");
+    }
+
+    #[test]
+    fn test_version_1_file() {
+        // Version 1 kept rows in insertion order, 14 to a page.
+        let (_dir, path) = create_test_db_file();
+        let mut bytes: Vec<u8> = (1..=14)
+            .rev()
+            .flat_map(|id| version_1_row(id, &format!("user{id}"), "a@b.com"))
+            .collect();
+        bytes.resize(Schema::DEFAULT_PAGE_SIZE, 0);
+        bytes.extend(version_1_row(15, "bob", "b@b.com"));
+        std::fs::write(&path, bytes).unwrap();
+
+        let scripts = [
+            "select where id > 12",
+            "select count",
+            "insert 16 carol c@b.com 30",
+            ".exit",
+        ];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (13 user13 a@b.com 0)\n\
+             (14 user14 a@b.com 0)\n\
+             (15 bob b@b.com 0)\n\
+             mysqlite> 15\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> "
+        );
+
+        // Closing rewrote the file in the current format.
+        let schema = Schema::default();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..Schema::DEFAULT_PAGE_SIZE], &schema.header()[..]);
+        assert_eq!(
+            bytes.len(),
+            2 * Schema::DEFAULT_PAGE_SIZE + 3 * schema.row_size()
+        );
+        let output = run_scripts(&["select where id < 3 or id > 14", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 user1 a@b.com 0)\n\
+             (2 user2 a@b.com 0)\n\
+             (15 bob b@b.com 0)\n\
+             (16 carol c@b.com 30)\n\
+             mysqlite> "
+        );
+    }
+
+    /// Lays out a row as version 1 files store it: the id followed by the
+    /// username and email, NUL-padded to 32 and 255 bytes.
+    fn version_1_row(id: u32, username: &str, email: &str) -> Vec<u8> {
+        let mut row = id.to_le_bytes().to_vec();
+        for (text, size) in [(username, 32), (email, 255)] {
+            let start = row.len();
+            row.extend_from_slice(text.as_bytes());
+            row.resize(start + size, 0);
+        }
+        row
+    }
+
+    #[test]
+    fn test_reopen_after_full_page() {
+        let inserts: Vec<_> = (1..=15)
+            .map(|i| format!("insert {i} user{i} person{i}@example.com"))
+            .collect();
+        let mut scripts: Vec<_> = inserts.iter().map(String::as_str).collect();
+        scripts.push(".exit");
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&scripts, &path).unwrap();
+
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        let rows: String = (1..=15)
+            .map(|i| format!("({i} user{i} person{i}@example.com NULL)\n"))
+            .collect();
+        assert_eq!(output, format!("mysqlite> {rows}mysqlite> "));
+    }
+
+    #[test]
+    fn test_select_length() {
+        let scripts = [
+            "insert 1 user1 foo@bar.com",
+            "select length(email)",
+            "select length(id)",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (11)\n\
+             mysqlite> Type mismatch.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_insert_age() {
+        let scripts = [
+            "insert 1 u a@b.com 30",
+            "insert 2 v c@d.com",
+            "insert 3 w e@f.com old",
+            "select",
+            "select age",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> (1 u a@b.com 30)\n\
+             (2 v c@d.com NULL)\n\
+             mysqlite> (30)\n\
+             (NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_where_like_unquoted_prefix() {
+        let scripts = [
+            "insert 1 alice alice@example.com",
+            "insert 2 alan alan@example.com",
+            "insert 3 bob bob@example.com",
+            "select where username like al%",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice alice@example.com NULL)\n\
+             (2 alan alan@example.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_substr() {
+        let scripts = [
+            "insert 1 user1 person1@example.com",
+            "select substr(email, 1, 6)",
+            "select substr(email, 0, 3)",
+            "select substr(email, 9, 100)",
+            "select substr(email, 50, 5)",
+            "select substr(email, 1)",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (person)\n\
+             mysqlite> (per)\n\
+             mysqlite> (example.com)\n\
+             mysqlite> ('')\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_gen_rows() {
+        let scripts = [
+            ".gen 100",
+            "select count(*)",
+            "select count(*) where id like 1%",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+        // The time taken varies, so only the rest of the output is exact.
+        let (generated, rest) = output.split_once('\n').unwrap();
+
+        assert!(generated.starts_with("mysqlite> Generated 100 rows in "));
+        assert_eq!(rest, "mysqlite> 100\nmysqlite> 12\nmysqlite> ");
+
+        let output = run_scripts(&["select count(*)", ".exit"], &path).unwrap();
+        assert_eq!(output, "mysqlite> 100\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_gen_rows_max_rows() {
+        let (_dir, path) = create_test_db_file();
+        let table = TableOptions::default().max_rows(5).open(&path).unwrap();
+        let input = "insert 1 a a@b.com\n.gen 10\ninsert 9 b b@b.com\nselect id\n.exit";
+        let mut output = vec![];
+        run(&mut io::Cursor::new(input), &mut output, table, true, None).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // The time taken varies, so only the rest of the output is exact.
+        let (before, rest) = output.split_once(" rows in ").unwrap();
+        let (_, rest) = rest.split_once('\n').unwrap();
+
+        assert_eq!(before, "mysqlite> Executed. (1 row)\nmysqlite> Generated 4");
+        assert_eq!(
+            rest,
+            "Table is full; 6 rows were not generated.\n\
+             mysqlite> Error: table is full (5 rows)\n\
+             mysqlite> (1)\n\
+             (2)\n\
+             (3)\n\
+             (4)\n\
+             (5)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_gen_rows_after_gap() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&["insert 5 a a@b.com", ".gen 3", ".exit"], &path).unwrap();
+
+        let scripts = ["select id", ".check", ".exit"];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (5)\n\
+             (6)\n\
+             (7)\n\
+             (8)\n\
+             mysqlite> No problems found.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_coalesce() {
+        let scripts = [
+            "insert 1 alice null",
+            "insert 2 bob bob@example.com",
+            "insert 3 null null",
+            "select coalesce(email, username)",
+            "select coalesce(email, username, 'nobody')",
+            "select coalesce(email, null)",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (alice)\n\
+             (bob@example.com)\n\
+             (NULL)\n\
+             mysqlite> (alice)\n\
+             (bob@example.com)\n\
+             (nobody)\n\
+             mysqlite> (NULL)\n\
+             (bob@example.com)\n\
+             (NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_min_max() {
+        let scripts = [
+            "insert 10 bob bob@example.com 40",
+            "insert 9 alice alice@example.com",
+            "insert 2 alan alan@example.com 25",
+            "select min(id)",
+            "select max(id)",
+            "select min(username)",
+            "select max(age)",
+            "select max(id) where username like 'a%'",
+            "select min(id) where username like 'z%'",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> min(id): 2\n\
+             mysqlite> max(id): 10\n\
+             mysqlite> min(username): alan\n\
+             mysqlite> max(age): 40\n\
+             mysqlite> max(id): 9\n\
+             mysqlite> min(id): NULL\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_broken_pipe_flushes_table() {
+        /// Accepts `lines` lines of output and fails every write after that.
+        struct BrokenPipeAfter {
+            lines: usize,
+        }
+
+        impl io::Write for BrokenPipeAfter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.lines == 0 {
+                    return Err(io::ErrorKind::BrokenPipe.into());
+                }
+                self.lines -= buf.iter().filter(|&&b| b == b'\n').count().min(self.lines);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (_dir, path) = create_test_db_file();
+        let mut input = io::Cursor::new("insert 1 a a@b.com\ninsert 2 b b@b.com\nselect\n");
+        let mut output = BrokenPipeAfter { lines: 3 };
+        run(
+            &mut input,
+            &mut output,
+            TableOptions::default().open(&path).unwrap(),
+            true,
+            None,
+        )
+        .unwrap();
+
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 a a@b.com NULL)\n\
+             (2 b b@b.com NULL)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_atomic_close() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.atomic_close = true;
+        table
+            .insert(
+                &Row::new(&Schema::default(), 1, "a", "a@b.com", 20)
+                    .ok()
+                    .unwrap(),
+            )
+            .unwrap();
+        table.close().unwrap();
+
+        // The file renamed into place stays locked until the table is gone.
+        assert!(matches!(
+            TableOptions::default()
+                .open(&path)
+                .err()
+                .unwrap()
+                .downcast_ref(),
+            Some(MysqliteError::DatabaseLocked)
+        ));
+        drop(table);
+
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(output, "mysqlite> (1 a a@b.com 20)\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_atomic_close_failure_keeps_original() {
+        let (dir, path) = create_test_db_file();
+        run_scripts(&["insert 1 a a@b.com", ".exit"], &path).unwrap();
+        let original = std::fs::read(&path).unwrap();
+
+        // Simulate a crash after the snapshot is written but before the
+        // rename: the original file must be left exactly as it was.
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table
+            .insert(
+                &Row::new(&Schema::default(), 2, "b", "b@b.com", 30)
+                    .ok()
+                    .unwrap(),
+            )
+            .unwrap();
+        table
+            .write_snapshot(&dir.path().join("test.db.tmp"))
+            .unwrap();
+        // A crash wouldn't get to flush on drop either.
+        std::mem::forget(table);
+
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+        let snapshot = std::fs::read(dir.path().join("test.db.tmp")).unwrap();
+        assert_eq!(
+            snapshot.len(),
+            Schema::DEFAULT_PAGE_SIZE + 2 * Schema::default().row_size()
+        );
+    }
+
+    /// A writer whose contents stay readable after it's handed off as a log.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_verbose_log() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&["insert 1 a a@b.com", ".exit"], &path).unwrap();
+
+        let log = SharedBuffer::default();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.pager.log = Some(Box::new(log.clone()));
+        table.pager.get_page_readonly(1).unwrap();
+        table.pager.get_page(1).unwrap();
+        table.pager.get_page(2).unwrap();
+        table
+            .pager
+            .flush_page(1, Schema::default().row_size())
+            .unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&log.0.borrow()).unwrap(),
+            "pager: page 1: miss, read 294 bytes\n\
+             pager: page 1: hit\n\
+             pager: page 2: miss, new page\n\
+             pager: page 1: flushed 294 bytes\n"
+        );
+    }
+
+    #[test]
+    fn test_drop_persists_rows() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        generate_rows(&mut table, 20).unwrap();
+        drop(table);
+
+        let output = run_scripts(&["select count(*)", ".exit"], &path).unwrap();
+        assert_eq!(output, "mysqlite> 20\nmysqlite> ");
+    }
+
+    #[test]
+    fn test_close_full_sync() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default()
+            .sync_mode(SyncMode::None)
+            .open(&path)
+            .unwrap();
+        generate_rows(&mut table, 3).unwrap();
+        table.close_full_sync().unwrap();
+        drop(table);
+
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (Schema::DEFAULT_PAGE_SIZE + 3 * Schema::default().row_size()) as u64
+        );
+    }
+
+    #[test]
+    fn test_create_if_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing.db");
+        let options = TableOptions::default().create_if_missing(false);
+
+        let err = Table::with_options(&path, options).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_check() {
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(
+            &[
+                "insert 1 a a@b.com",
+                "insert 2 b b@b.com",
+                ".check",
+                ".exit",
+            ],
+            &path,
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> No problems found.\nmysqlite> "
+        );
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        let schema = Schema::default();
+        bytes[Schema::DEFAULT_PAGE_SIZE
+            + schema.row_size()
+            + schema.field(ColumnSelector::Username).0] = 0xff;
+        std::fs::write(&path, bytes).unwrap();
+
+        let output = run_scripts(&[".check", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> row 1: username is not valid UTF-8\n\
+             1 problem found.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_run_script_until_eof() {
+        let (dir, path) = create_test_db_file();
+        let script = dir.path().join("script.sql");
+        std::fs::write(&script, "insert 1 a a@b.com\ninsert 2 b b@b.com\n").unwrap();
+
+        let mut input = io::BufReader::new(std::fs::File::open(&script).unwrap());
+        let mut output = vec![];
+        run(
+            &mut input,
+            &mut output,
+            TableOptions::default().open(&path).unwrap(),
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "Executed. (1 row)\nExecuted. (1 row)\n"
+        );
+
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 a a@b.com NULL)\n(2 b b@b.com NULL)\nmysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_mode_column() {
+        let scripts = [
+            "insert 1 alice alice@example.com 30",
+            "insert 22 bob a.very.long.email.address@example.com",
+            ".mode column",
+            "select",
+            "select username, count(*) group by username",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> mysqlite> id  username  email                           age\n\
+             --  --------  ------------------------------  ----\n\
+             1   alice     alice@example.com               30\n\
+             22  bob       a.very.long.email.address@e...  NULL\n\
+             mysqlite> username  count(*)\n\
+             --------  --------\n\
+             alice     1\n\
+             bob       1\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_sync_off() {
+        let (_dir, path) = create_test_db_file();
+        let scripts = ["insert 1 a a@b.com", ".sync off", ".exit"];
+        let log = SharedBuffer::default();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.pager.log = Some(Box::new(log.clone()));
+        let input = scripts.join("\n");
+        run(&mut io::Cursor::new(input), &mut vec![], table, false, None).unwrap();
+
+        let log = String::from_utf8(log.0.take()).unwrap();
+        assert!(log.contains("pager: page 1: flushed 294 bytes\n"));
+        assert!(!log.contains("synced"));
+
+        let scripts = ["insert 2 b b@b.com", ".exit"];
+        let log = SharedBuffer::default();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.pager.log = Some(Box::new(log.clone()));
+        let input = scripts.join("\n");
+        run(&mut io::Cursor::new(input), &mut vec![], table, false, None).unwrap();
+
+        let log = String::from_utf8(log.0.take()).unwrap();
+        assert!(log.ends_with("pager: synced data\n"));
+    }
+
+    #[test]
+    fn test_read_script() {
+        let (dir, path) = create_test_db_file();
+        let fixtures = dir.path().join("fixtures.sql");
+        let nested = dir.path().join("nested.sql");
+        let recursive = dir.path().join("recursive.sql");
+        std::fs::write(
+            &fixtures,
+            format!(
+                "insert 1 a a@b.com\nbogus\n.read {}\n.mode line\n",
+                nested.display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(&nested, "insert 2 b b@b.com\n").unwrap();
+        std::fs::write(&recursive, format!(".read {}\n", recursive.display())).unwrap();
+
+        let read_fixtures = format!(".read {}", fixtures.display());
+        let read_recursive = format!(".read {}", recursive.display());
+        let scripts = [
+            read_fixtures.as_str(),
+            "select id",
+            read_recursive.as_str(),
+            ".read missing.sql",
+            ".exit",
+        ];
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             Unrecognized keyword at start of 'bogus'.\n\
+             Executed. (1 row)\n\
+             mysqlite> id = 1\n\
+             \n\
+             id = 2\n\
+             mysqlite> Error: .read nested more than 16 deep\n\
+             mysqlite> Cannot open 'missing.sql': No such file or directory (os error 2)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_read_input_retries_interrupted() {
+        /// Fails its first line read with `Interrupted`, like a read cut short
+        /// by a signal.
+        struct InterruptedOnce {
+            inner: io::Cursor<&'static str>,
+            interrupted: bool,
+        }
+
+        impl io::Read for InterruptedOnce {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.inner.read(buf)
+            }
+        }
+
+        impl io::BufRead for InterruptedOnce {
+            fn fill_buf(&mut self) -> io::Result<&[u8]> {
+                self.inner.fill_buf()
+            }
+
+            fn consume(&mut self, amount: usize) {
+                self.inner.consume(amount);
+            }
+
+            fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+                if !self.interrupted {
+                    self.interrupted = true;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                self.inner.read_line(buf)
             }
+        }
+
+        let mut input = InterruptedOnce {
+            inner: io::Cursor::new("select\n"),
+            interrupted: false,
+        };
+        let mut buffer = String::new();
+        assert_eq!(read_input(&mut input, &mut buffer).unwrap(), Some("select"));
+        assert_eq!(read_input(&mut input, &mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_line_editor() {
+        let keys = concat!(
+            // Typing, a backspace, and a correction after moving left.
+            "sel\x7flect\r",
+            "insert 1 a a@b.cm\x1b[Do\x1b[C\r",
+            // Up twice recalls the first line; down returns to the draft.
+            "\x1b[A\x1b[A\x1b[Bx\x1b[B\x15.exit\r",
+            // Ctrl-A, Ctrl-E and delete under the cursor.
+            "\x1b[A\x1b[A\x1b[A\x01\x1b[3~S\x05;\r",
+            // Ctrl-C drops the line; Ctrl-D on an empty line ends the input.
+            "junk\x03\x04",
+        );
+        let mut editor = LineEditor {
+            keys: io::Cursor::new(keys.as_bytes()),
+            echo: vec![],
+            raw_mode: false,
+            history: vec![],
+            history_file: None,
+            line: vec![],
+            consumed: 0,
+        };
+
+        let mut buffer = String::new();
+        let mut lines = vec![];
+        while let Some(line) = read_input(&mut editor, &mut buffer).unwrap() {
+            lines.push(line.to_string());
+        }
+        assert_eq!(lines, ["select", "insert 1 a a@b.com", ".exit", "Select;"]);
+        assert_eq!(editor.history, lines);
+    }
+
+    #[test]
+    fn test_database_path() {
+        let dir = TempDir::new().unwrap();
+        let path = database_path(Path::new("test.db"), Some(dir.path()));
+        assert_eq!(path, dir.path().join("test.db"));
+        run_scripts(&["insert 1 a a@b.com", ".exit"], &path).unwrap();
+        assert!(dir.path().join("test.db").exists());
+
+        let absolute = dir.path().join("other.db");
+        assert_eq!(
+            database_path(&absolute, Some(Path::new("/elsewhere"))),
+            absolute
+        );
+        assert_eq!(
+            database_path(Path::new("test.db"), None),
+            Path::new("test.db")
+        );
+    }
+
+    #[test]
+    fn test_init_script() {
+        let (dir, path) = create_test_db_file();
+        let init = dir.path().join("init.sql");
+        std::fs::write(&init, "insert 1 a a@b.com\nbogus\n.mode line\n").unwrap();
+
+        let input = "insert 2 b b@b.com\nselect id\n.exit\n";
+        let mut output = vec![];
+        let table = TableOptions::default().open(&path).unwrap();
+        run(
+            &mut io::Cursor::new(input),
+            &mut output,
+            table,
+            true,
+            Some(&init),
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "Executed. (1 row)\n\
+             Unrecognized keyword at start of 'bogus'.\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> id = 1\n\
+             \n\
+             id = 2\n\
+             mysqlite> "
+        );
+
+        // A missing init script is reported and the session goes on.
+        let missing = dir.path().join("missing.sql");
+        let mut output = vec![];
+        let table = TableOptions::default().open(&path).unwrap();
+        run(
+            &mut io::Cursor::new("select id\n"),
+            &mut output,
+            table,
+            false,
+            Some(&missing),
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            format!(
+                "Cannot open '{}': No such file or directory (os error 2)\n(1)\n(2)\n",
+                missing.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_readonly() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&["insert 1 a a@b.com 20", ".exit"], &path).unwrap();
+        let original = std::fs::read(&path).unwrap();
+
+        let scripts = [
+            "insert 2 b b@b.com",
+            "update 1 set username = z",
+            "delete from",
+            ".clear",
+            ".gen 3",
+            "select",
+            ".exit",
+        ];
+        let input = scripts.join("\n");
+        let mut output = vec![];
+        let table = Table::with_options(&path, TableOptions::default().read_only(true)).unwrap();
+        assert!(matches!(
+            TableOptions::default()
+                .open(&path)
+                .err()
+                .unwrap()
+                .downcast_ref(),
+            Some(MysqliteError::DatabaseLocked)
+        ));
+        run(&mut io::Cursor::new(input), &mut output, table, true, None).unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "mysqlite> Error: database is read-only\n\
+             mysqlite> Error: database is read-only\n\
+             mysqlite> Error: database is read-only\n\
+             mysqlite> Error: database is read-only\n\
+             mysqlite> Error: database is read-only\n\
+             mysqlite> (1 a a@b.com 20)\n\
+             mysqlite> "
+        );
+        assert_eq!(std::fs::read(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn test_lock_excludes_second_writer() {
+        let (_dir, path) = create_test_db_file();
+        let table = TableOptions::default().open(&path).unwrap();
+
+        let handle = {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                TableOptions::default()
+                    .open(&path)
+                    .err()
+                    .map(|err| err.to_string())
+            })
+        };
+        assert_eq!(
+            handle.join().unwrap().as_deref(),
+            Some("database is locked")
+        );
+        let readonly = TableOptions::default().read_only(true);
+        assert!(matches!(
+            Table::with_options(&path, readonly)
+                .err()
+                .unwrap()
+                .downcast_ref(),
+            Some(MysqliteError::DatabaseLocked)
+        ));
+
+        drop(table);
+        assert!(TableOptions::default().open(&path).is_ok());
+    }
+
+    #[test]
+    fn test_delete_all() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        generate_rows(&mut table, 20).unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        let scripts = ["delete from", "select", "insert 1 a a@b.com", ".exit"];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (20 rows)\n\
+             mysqlite> mysqlite> Executed. (1 row)\n\
+             mysqlite> "
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (Schema::DEFAULT_PAGE_SIZE + Schema::default().row_size()) as u64
+        );
+
+        let output = run_scripts(&[".clear", "select", ".exit"], &path).unwrap();
+        assert_eq!(output, "mysqlite> mysqlite> mysqlite> ");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            Schema::DEFAULT_PAGE_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn test_close_without_writes_leaves_file_untouched() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&["insert 1 a a@b.com 20", ".exit"], &path).unwrap();
+
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let output = run_scripts(&["select", "select count(*)", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 a a@b.com 20)\nmysqlite> 1\nmysqlite> "
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), old);
+    }
+
+    #[test]
+    fn test_batch_mode() {
+        let (_dir, path) = create_test_db_file();
+        let mut input =
+            io::Cursor::new("insert 2 b b@b.com\ninsert 1 a a@b.com 20\nselect\n.exit\n");
+        let mut output = vec![];
+        run(
+            &mut input,
+            &mut output,
+            TableOptions::default().open(&path).unwrap(),
+            false,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "Executed. (1 row)\n\
+             Executed. (1 row)\n\
+             (1 a a@b.com 20)\n\
+             (2 b b@b.com NULL)\n"
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        for id in (2..=200).step_by(2) {
+            table
+                .insert(
+                    &Row::new(&Schema::default(), id, "a", "a@b.com", 20)
+                        .ok()
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+
+        assert_eq!(table.find(2).unwrap(), Some(0));
+        assert_eq!(table.find(64).unwrap(), Some(31));
+        assert_eq!(table.find(200).unwrap(), Some(99));
+        assert_eq!(table.find(1).unwrap(), None);
+        assert_eq!(table.find(63).unwrap(), None);
+        assert_eq!(table.find(201).unwrap(), None);
+
+        let mut output = Vec::new();
+        let filter = "id = 64".parse().ok();
+        table
+            .select(&[], filter.as_ref(), false, OutputMode::Tuple, &mut output)
+            .unwrap();
+        assert_eq!(output, b"(64 a a@b.com 20)\n");
+    }
+
+    #[test]
+    fn test_iter_rows() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 20", ".exit"], &path).unwrap();
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+
+        let mut table = TableOptions::default().open(&path).unwrap();
+        let rows: Result<Vec<Row>, MysqliteError> = table.iter_rows().collect();
+        let rows = rows.unwrap();
+        assert_eq!(rows.len(), 20);
+        assert_eq!(
+            rows[0],
+            Row::new(&Schema::default(), 1, "user1", "person1@example.com", 1)
+                .ok()
+                .unwrap()
+        );
+
+        let listed: String = rows.iter().map(|row| format!("{row}\n")).collect();
+        assert_eq!(output, format!("mysqlite> {listed}mysqlite> "));
+    }
+
+    #[test]
+    fn test_iter_rows_where_reads_few_pages() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 1000", ".exit"], &path).unwrap();
+
+        let mut table = TableOptions::default().open(&path).unwrap();
+        assert!(table.pager.pages.is_empty());
+
+        // Rows 899 to 904 share a page, and the binary search only peeks at
+        // the ids of the rows it probes, so that page is the only one loaded.
+        let filter = "id between 900 and 905".parse().ok().unwrap();
+        let rows = table
+            .iter_rows_where(&filter)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let ids: Vec<_> = rows.iter().map(|row| row.id).collect();
+        assert_eq!(ids, [900, 901, 902, 903, 904, 905]);
+
+        let (page_num, _) = table.row_location(899);
+        assert_eq!(table.row_location(904).0, page_num);
+        assert_eq!(table.pager.pages.len(), 1);
+        assert!(table.pager.pages.contains_key(&page_num));
+    }
+
+    #[test]
+    fn test_get_raw_row() {
+        let scripts = [
+            "insert 258 ab null 20",
+            ".get 258",
+            ".get 7",
+            ".get x",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        let username = format!("61 62{}", " 00".repeat(30));
+        let email = vec!["00"; 255].join(" ");
+        assert_eq!(
+            output,
+            format!(
+                "mysqlite> Executed. (1 row)\n\
+                 mysqlite> nulls: 04\n\
+                 id: 02 01 00 00\n\
+                 username: {username}\n\
+                 email: {email}\n\
+                 age: 14 00\n\
+                 mysqlite> Row 7 does not exist.\n\
+                 mysqlite> Unrecognized command '.get x'\n\
+                 mysqlite> "
+            )
+        );
+    }
+
+    #[test]
+    fn test_pagecount() {
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&[".pagecount", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Pages in use: 1\nPages in file: 0\nmysqlite> "
+        );
+
+        // The header was written on close, but the new rows fill two more
+        // pages, which aren't written until the next one.
+        let mut table = TableOptions::default().open(&path).unwrap();
+        generate_rows(&mut table, 14).unwrap();
+        let mut output = vec![];
+        let mut input = io::Cursor::new(".pagecount\n.exit");
+        run(&mut input, &mut output, table, true, None).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "mysqlite> Pages in use: 3\nPages in file: 1\nmysqlite> "
+        );
+
+        let table = TableOptions::default().open(&path).unwrap();
+        assert!(table.pager.page_count() >= 3);
+        assert_eq!(table.pager.file_page_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 20", ".exit"], &path).unwrap();
+
+        let scripts = [
+            ".stats",
+            "select where id = 1",
+            "insert 21 a a@b.com",
+            ".stats",
+            ".exit",
+        ];
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> rows: 20\n\
+             page size: 4096\n\
+             pages on disk: 3\n\
+             pages cached: 0\n\
+             pages dirty: 0\n\
+             fill: 71.8%\n\
+             mysqlite> (1 user1 person1@example.com 1)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> rows: 21\n\
+             page size: 4096\n\
+             pages on disk: 3\n\
+             pages cached: 2\n\
+             pages dirty: 1\n\
+             fill: 75.4%\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_cache_capacity() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 100", ".exit"], &path).unwrap();
+        let expected = TableOptions::default()
+            .open(&path)
+            .unwrap()
+            .iter_rows()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let mut table = TableOptions::default()
+            .cache_capacity(2)
+            .open(&path)
+            .unwrap();
+        let rows = table.iter_rows().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows, expected);
+        assert_eq!(table.pager.pages.len(), 2);
+
+        // Dirty pages stay cached until they're flushed.
+        table
+            .update_where(&[(ColumnSelector::Age, None)], None)
+            .unwrap();
+        assert_eq!(table.pager.pages.len(), 8);
+        table.close().unwrap();
+        drop(table);
+
+        let mut table = TableOptions::default().open(&path).unwrap();
+        let rows = table.iter_rows().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.len(), 100);
+        assert!(rows.iter().all(|row| row.is_null(ColumnSelector::Age)));
+    }
+
+    #[test]
+    fn test_schema_in_header() {
+        let (_dir, path) = create_test_db_file();
+        let schema = Schema {
+            username_size: 64,
+            ..Schema::default()
+        };
+        let username = "u".repeat(40);
+        let input = format!(
+            "insert 1 {username} a@b.com\ninsert 2 {} a@b.com\n.exit",
+            "u".repeat(65)
+        );
+        let mut output = vec![];
+        let table = TableOptions::default().schema(schema).open(&path).unwrap();
+        run(
+            &mut io::Cursor::new(&input[..]),
+            &mut output,
+            table,
+            true,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> String is too long.\n\
+             mysqlite> "
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (Schema::DEFAULT_PAGE_SIZE + schema.row_size()) as u64
+        );
+
+        // The sizes come from the header, not the options it's opened with.
+        let table = TableOptions::default().open(&path).unwrap();
+        assert_eq!(table.schema, schema);
+        assert_eq!(table.row_location(0), (1, 0));
+        drop(table);
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            format!("mysqlite> (1 {username} a@b.com NULL)\nmysqlite> ")
+        );
+    }
+
+    #[test]
+    fn test_page_size() {
+        let (_dir, path) = create_test_db_file();
+        let schema = Schema {
+            page_size: 512,
+            ..Schema::default()
+        };
+        let mut table = TableOptions::default().schema(schema).open(&path).unwrap();
+        for id in 1..=3 {
+            table
+                .insert(
+                    &Row::new(&Schema::default(), id, "a", "a@b.com", 20)
+                        .ok()
+                        .unwrap(),
+                )
+                .unwrap();
+        }
+        assert_eq!(table.row_location(2), (3, 0));
+        table.close().unwrap();
+        drop(table);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 4 * 512);
+
+        let table = TableOptions::default().open(&path).unwrap();
+        assert_eq!(table.schema, schema);
+        drop(table);
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (1 a a@b.com 20)\n(2 a a@b.com 20)\n(3 a a@b.com 20)\nmysqlite> "
+        );
+
+        let schema = Schema {
+            page_size: 1000,
+            ..Schema::default()
         };
+        let (_dir, path) = create_test_db_file();
+        assert!(TableOptions::default().schema(schema).open(&path).is_err());
+    }
+
+    #[test]
+    fn test_in_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("memory.db");
+        let table = TableOptions::default().in_memory(true).open(&path).unwrap();
+        let mut output = vec![];
+        run(
+            &mut io::Cursor::new("insert 1 a a@b.com\ninsert 2 b b@c.com\nselect\n.exit"),
+            &mut output,
+            table,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&output).unwrap(),
+            "Executed. (1 row)\nExecuted. (1 row)\n(1 a a@b.com NULL)\n(2 b b@c.com NULL)\n"
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_sparse_page_cache() {
+        let (_dir, path) = create_test_db_file();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.pager.get_page(0).unwrap();
+        table.pager.get_page_readonly(100).unwrap();
+
+        assert_eq!(table.pager.pages.len(), 2);
+        assert_eq!(table.pager.page_count(), 101);
+        assert!(table.pager.dirty.contains(&0));
+        assert!(!table.pager.dirty.contains(&100));
+        assert!(
+            table
+                .pager
+                .page_info(50)
+                .unwrap()
+                .is_some_and(|info| !info.loaded)
+        );
+    }
+
+    #[test]
+    fn test_pageinfo() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&["insert 1 a a@b.com 20", ".exit"], &path).unwrap();
+
+        let scripts = [
+            ".pageinfo 1",
+            "select",
+            ".pageinfo 1",
+            "insert 2 b b@b.com",
+            ".pageinfo 1",
+            ".pageinfo 2",
+            ".exit",
+        ];
+        let output = run_scripts(&scripts, &path).unwrap();
+        let head = "0000: 00 01 00 00 00 61 00 00 00 00 00 00 00 00 00 00\n\
+                    0010: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+                    0020: 00 00 00 00 00 61 40 62 2e 63 6f 6d 00 00 00 00\n\
+                    0030: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n";
+        assert_eq!(
+            output,
+            format!(
+                "mysqlite> page: 1\nloaded: false\ndirty: false\n{head}\
+                 mysqlite> (1 a a@b.com 20)\n\
+                 mysqlite> page: 1\nloaded: true\ndirty: false\n{head}\
+                 mysqlite> Executed. (1 row)\n\
+                 mysqlite> page: 1\nloaded: true\ndirty: true\n{head}\
+                 mysqlite> Page 2 does not exist.\n\
+                 mysqlite> "
+            )
+        );
+    }
+
+    #[test]
+    fn test_insert_keeps_rows_sorted() {
+        let scripts = [
+            "insert 3 c c@b.com",
+            "insert 1 a a@b.com",
+            "insert 2 b b@b.com",
+            "insert null n n@b.com",
+            "insert null m m@b.com",
+            "insert 2 d d@b.com",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "{}mysqlite> Error: duplicate id 2\nmysqlite> ",
+                "mysqlite> Executed. (1 row)\n".repeat(5)
+            )
+        );
+
+        let output = run_scripts(&["select id username", ".check", ".exit"], &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> (NULL n)\n\
+             (NULL m)\n\
+             (1 a)\n\
+             (2 b)\n\
+             (3 c)\n\
+             mysqlite> No problems found.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_where_is_null() {
+        let scripts = [
+            "insert 1 alice null",
+            "insert 2 bob b@b.com",
+            "insert 3 null c@b.com",
+            "select id where email is null",
+            "select id where not email IS NULL",
+            "select id where email is null or username is null",
+            "select id where age is",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1)\n\
+             mysqlite> (2)\n\
+             (3)\n\
+             mysqlite> (1)\n\
+             (3)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_where_is_not_null() {
+        let scripts = [
+            "insert 1 alice null 30",
+            "insert 2 bob b@b.com",
+            "select id where email is not null",
+            "select id where age is not null and email is not null",
+            "select count(*) where age is not null",
+            "explain select where username is not null",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (2)\n\
+             mysqlite> mysqlite> 1\n\
+             mysqlite> Table scan: rows (2 rows), filter: username is not null\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_where_between() {
+        let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 5", ".exit"], &path).unwrap();
+        let scripts = [
+            "select id where id between 2 and 4",
+            "select id where id between 4 and 2",
+            "select id where id between -10 and 1",
+            "select id where username between 'user3' and 'user9'",
+            "select count(*) where not id between 2 and 4",
+            "explain select where id between 2 and 4",
+            ".exit",
+        ];
+        let output = run_scripts(&scripts, &path).unwrap();
 
-        execute_statement(&statement, &mut table, output)?;
+        assert_eq!(
+            output,
+            "mysqlite> (2)\n(3)\n(4)\n\
+             mysqlite> mysqlite> (1)\n\
+             mysqlite> (3)\n(4)\n(5)\n\
+             mysqlite> 2\n\
+             mysqlite> Binary search: rows (5 rows), filter: id between 2 and 4\n\
+             mysqlite> "
+        );
     }
-}
 
-#[derive(Parser, Debug)]
-struct Args {
-    #[arg(default_value = "mysqlite.db")]
-    filename: PathBuf,
-}
+    #[test]
+    fn test_count_filtered() {
+        let scripts = [
+            "insert 1 alice a@b.com",
+            "insert 2 bob b@b.com",
+            "insert 3 alice c@b.com",
+            "insert 4 bob d@b.com",
+            "insert 5 alice e@b.com",
+            "select count where username = alice",
+            "select count(*) where username = bob",
+            "select count",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
-    let filename = args.filename.as_path();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> 3\nmysqlite> 2\nmysqlite> 5\nmysqlite> "
+        );
+    }
 
-    let mut stdin = io::stdin().lock();
-    let mut stdout = io::stdout().lock();
-    run(&mut stdin, &mut stdout, filename)
-}
+    #[test]
+    fn test_count_username_prefix() {
+        let scripts = [
+            "insert 1 alice a@b.com",
+            "insert 2 alan b@b.com",
+            "insert 3 bob c@b.com",
+            "insert 4 albert d@b.com",
+            "select count(*) where username like al%",
+            "select count(*) where username like 'b%'",
+            "select count(*) where username like z%",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
 
-#[cfg(test)]
-mod tests {
-    use std::path::{Path, PathBuf};
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> 3\nmysqlite> 1\nmysqlite> 0\nmysqlite> "
+        );
+    }
 
-    use tempfile::TempDir;
+    #[test]
+    fn test_delete_where() {
+        let scripts = [
+            "insert 1 alice a@b.com",
+            "insert 2 bob b@b.com",
+            "insert 3 alice c@b.com",
+            "insert 4 carol d@b.com",
+            "insert 5 alice e@b.com",
+            "delete from rows where username = 'alice'",
+            "select count",
+            "select where username = alice",
+            "delete from where id between 4 and 9",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (3 rows)\n\
+             mysqlite> 2\n\
+             mysqlite> mysqlite> Executed. (1 row)\n\
+             mysqlite> (2 bob b@b.com NULL)\n\
+             mysqlite> "
+        );
 
-    use super::{Error, io, run};
+        // The file shrinks to the rows that are left.
+        let output = run_scripts(&["select", ".exit"], &path).unwrap();
+        assert_eq!(output, "mysqlite> (2 bob b@b.com NULL)\nmysqlite> ");
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (Schema::DEFAULT_PAGE_SIZE + Schema::default().row_size()) as u64
+        );
+    }
 
     #[test]
-    fn test_simple_insert_and_select() {
-        let scripts = ["insert 1 user1 person1@example.com", "select", ".exit"];
+    fn test_truncate() {
         let (_dir, path) = create_test_db_file();
+        run_scripts(&[".gen 100", ".exit"], &path).unwrap();
+        let scripts = ["truncate table rows", "select", ".exit"];
         let output = run_scripts(&scripts, &path).unwrap();
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (100 rows)\nmysqlite> mysqlite> "
+        );
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            Schema::DEFAULT_PAGE_SIZE as u64
+        );
 
+        let output = run_scripts(&["truncate table", "select", ".exit"], &path).unwrap();
         assert_eq!(
             output,
-            "mysqlite> mysqlite> (1 user1 person1@example.com)\nmysqlite> "
+            "mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> mysqlite> "
         );
     }
 
     #[test]
-    fn test_username_max_length() {
+    fn test_explain() {
         let scripts = [
-            "insert 1 abcdefghijklmnopqrstuvwxyzabcdef a@b.com",
+            "insert 1 alice a@b.com 20",
+            "explain select where id = 5",
+            "explain select username, count(*) where username like 'a%' group by username",
+            "explain select count(*)",
+            "explain insert 2 bob b@b.com",
+            "explain select id union select id where id in (select id where username = 'bob')",
             "select",
             ".exit",
         ];
@@ -411,64 +6811,588 @@ mod tests {
 
         assert_eq!(
             output,
-            "mysqlite> mysqlite> (1 abcdefghijklmnopqrstuvwxyzabcdef a@b.com)\nmysqlite> "
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Binary search: rows (1 row), filter: id = 5\n\
+             mysqlite> Table scan: rows (1 row), filter: username like 'a%', group by: username\n\
+             mysqlite> Row count: rows\n\
+             mysqlite> Insert into rows in id order: (2 bob b@b.com NULL)\n\
+             mysqlite> Compound select: union\n  \
+             Table scan: rows (1 row)\n  \
+             Table scan: rows (1 row), filter: id in (subquery)\n    \
+             Table scan: rows (1 row), filter: username = 'bob'\n\
+             mysqlite> (1 alice a@b.com 20)\n\
+             mysqlite> "
         );
     }
+
     #[test]
-    fn test_username_too_long() {
+    fn test_where_in_subquery() {
         let scripts = [
-            "insert 1 abcdefghijklmnopqrstuvwxyzabcdefg a@b.com",
+            "insert 1 alice a@b.com 20",
+            "insert 2 bob b@b.com 30",
+            "insert 3 alice c@b.com 40",
+            "insert 4 carol d@b.com",
+            "select id where id in (select id where username = 'alice')",
+            "select username where age in (select age where id > 1 union select age where id = 1) and not id = 3",
+            "select id where not age in (select age where id = 4)",
+            "select id where id in (select id, email)",
             ".exit",
         ];
         let (_dir, path) = create_test_db_file();
         let output = run_scripts(&scripts, &path).unwrap();
 
-        assert_eq!(output, "mysqlite> String is too long.\nmysqlite> ");
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1)\n\
+             (3)\n\
+             mysqlite> (alice)\n\
+             (bob)\n\
+             mysqlite> mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
     }
 
     #[test]
-    fn test_email_max_length() {
-        let n = 255;
-        let insert_str = &format!("insert 1 u {0:a<1$}", "", n);
-        let scripts = [insert_str, ".exit"];
+    fn test_output_redirect() {
+        let (dir, path) = create_test_db_file();
+        let results = dir.path().join("results.txt");
+        let scripts = [
+            "insert 1 a a@b.com 20".to_string(),
+            format!(".output {}", results.display()),
+            "select".to_string(),
+            "bogus".to_string(),
+            ".output stdout".to_string(),
+            "select id".to_string(),
+            ".output /nonexistent/results.txt".to_string(),
+            ".exit".to_string(),
+        ];
+        let scripts: Vec<_> = scripts.iter().map(String::as_str).collect();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&results).unwrap(),
+            "(1 a a@b.com 20)\n"
+        );
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> mysqlite> mysqlite> Unrecognized keyword at start of 'bogus'.\n\
+             mysqlite> mysqlite> (1)\n\
+             mysqlite> Cannot open '/nonexistent/results.txt': \
+             No such file or directory (os error 2)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_column_list() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "select id email",
+            "select age  id, username",
+            "select id substr(email, 1, 3)",
+            "select id phone",
+            "select id,",
+            ".exit",
+        ];
         let (_dir, path) = create_test_db_file();
         let output = run_scripts(&scripts, &path).unwrap();
 
-        assert_eq!(output, "mysqlite> mysqlite> ");
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 a@b.com)\n\
+             mysqlite> (20 1 alice)\n\
+             mysqlite> (1 a@b)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
     }
 
     #[test]
-    fn test_email_too_long() {
-        let n = 256;
-        let insert_str = &format!("insert 1 u {0:a<1$}", "", n);
-        let scripts = [insert_str, ".exit"];
+    fn test_select_except() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 5 bob b@b.com 30",
+            "insert 9 bob c@b.com 40",
+            "select except select where id = 5",
+            "select username except select username where id = 1",
+            "select id except select id where id > 1 except select id",
+            ".exit",
+        ];
         let (_dir, path) = create_test_db_file();
         let output = run_scripts(&scripts, &path).unwrap();
 
-        assert_eq!(output, "mysqlite> String is too long.\nmysqlite> ");
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice a@b.com 20)\n\
+             (9 bob c@b.com 40)\n\
+             mysqlite> (bob)\n\
+             mysqlite> mysqlite> "
+        );
     }
 
     #[test]
-    fn test_persistent_data() {
-        let scripts = ["insert 1 user1 person1@example.com", ".exit"];
+    fn test_select_intersect() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 5 bob b@b.com 30",
+            "insert 9 anna c@b.com 40",
+            "insert 12 adam d@b.com 50",
+            "insert 15 carl e@b.com 60",
+            "select where username like 'a%' intersect select where id < 10",
+            "select username where id > 1 intersect select username where id < 12 union select id where id = 15",
+            ".exit",
+        ];
         let (_dir, path) = create_test_db_file();
         let output = run_scripts(&scripts, &path).unwrap();
-        assert_eq!(output, "mysqlite> mysqlite> ");
 
-        let scripts = ["select", ".exit"];
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1 alice a@b.com 20)\n\
+             (9 anna c@b.com 40)\n\
+             mysqlite> (bob)\n\
+             (anna)\n\
+             (15)\n\
+             mysqlite> "
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_flush_failure_names_page() {
+        // Every write to /dev/full fails with ENOSPC.
+        let mut table = TableOptions::default().open("/dev/full").unwrap();
+        table
+            .insert(
+                &Row::new(&Schema::default(), 1, "a", "a@b.com", 20)
+                    .ok()
+                    .unwrap(),
+            )
+            .unwrap();
+
+        let err = table.close().unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("failed to persist page 0: No space left on device"),
+            "{err}"
+        );
+        // Dropping the table mustn't try, and report, the same close again.
+        assert!(table.closed);
+    }
+
+    #[test]
+    fn test_select_union() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 2 bob b@b.com 30",
+            "insert 3 carol c@b.com 40",
+            "select id where id < 3 union select id where id > 1",
+            "select id where id < 3 union all select id where id > 1",
+            "select username where id = 1 union select email where id = 2 union select age",
+            "select id union select id, username",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1)\n\
+             (2)\n\
+             (3)\n\
+             mysqlite> (1)\n\
+             (2)\n\
+             (2)\n\
+             (3)\n\
+             mysqlite> (alice)\n\
+             (b@b.com)\n\
+             (20)\n\
+             (30)\n\
+             (40)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_compound_keywords() {
+        let scripts = [
+            "insert into rows values (1, 'x union select', 'a@b.com')",
+            "insert 2 bob b@b.com",
+            "select id where username = 'x union select'",
+            "SELECT id WHERE id < 2 UNION ALL SELECT id WHERE id > 0",
+            "select id Intersect Select id where id in (SELECT id where id = 2)",
+            "select id EXCEPT select id where username = 'a except select'",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1)\n\
+             mysqlite> (1)\n\
+             (1)\n\
+             (2)\n\
+             mysqlite> (2)\n\
+             mysqlite> (1)\n\
+             (2)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_mode_line() {
+        let scripts = [
+            "insert 1 user1 person1@example.com 20",
+            "insert 2 user2 person2@example.com",
+            ".mode line",
+            "select",
+            "select id, upper(username) where id = 1",
+            ".mode tuple",
+            "select id",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> mysqlite> id = 1\n\
+             username = user1\n\
+             email = person1@example.com\n\
+             age = 20\n\
+             \n\
+             id = 2\n\
+             username = user2\n\
+             email = person2@example.com\n\
+             age = NULL\n\
+             mysqlite> id = 1\n\
+             upper(username) = USER1\n\
+             mysqlite> mysqlite> (1)\n\
+             (2)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_where_comparisons() {
+        let scripts = [
+            "insert 1 alice zed@b.com 20",
+            "insert 5 bob mia@b.com 30",
+            "insert 9 carol abe@b.com",
+            "select id where id > 1",
+            "select id where id < 5",
+            "select id where id >= 5",
+            "select id where id <= 5 and age > 20",
+            "select id where email >= 'm'",
+            "select id where username < bob",
+            "select where id > 'foo'",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (5)\n\
+             (9)\n\
+             mysqlite> (1)\n\
+             mysqlite> (5)\n\
+             (9)\n\
+             mysqlite> (5)\n\
+             mysqlite> (1)\n\
+             (5)\n\
+             mysqlite> (1)\n\
+             mysqlite> Type mismatch.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_where_and_or() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 2 bob b@b.com 30",
+            "insert 3 alice c@d.com 40",
+            "insert 4 carol d@d.com",
+            "select id where username = alice and email like '%b.com'",
+            "select id where username = bob and id = 1",
+            "select id where id = 2 or id = 4",
+            "select id where id = 5 or username = dave",
+            "select id where id = 1 or id = 2 and username = alice",
+            "select id where (id = 1 or id = 2) and username = alice",
+            "select id where username = alice and (email like '%d.com' or not age = 40)",
+            "select id where not (id = 1 or age = 30)",
+            "select id where (id = 1",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (1)\n\
+             mysqlite> mysqlite> (2)\n\
+             (4)\n\
+             mysqlite> mysqlite> (1)\n\
+             mysqlite> (1)\n\
+             mysqlite> (1)\n\
+             (3)\n\
+             mysqlite> (3)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_row_location() {
+        let (_dir, path) = create_test_db_file();
+        let table = TableOptions::default().open(&path).unwrap();
+        let (offset, size) = table.schema.field(ColumnSelector::Age);
+        assert_eq!(offset + size, table.schema.row_size());
+        assert_eq!(table.row_location(0), (1, 0));
+        assert_eq!(table.row_location(1), (1, 294));
+        assert_eq!(table.row_location(12), (1, 3528));
+        assert_eq!(table.row_location(13), (2, 0));
+        assert_eq!(table.row_location(27), (3, 294));
+    }
+
+    #[test]
+    fn test_get_page_after_flush() {
+        let (_dir, path) = create_test_db_file();
+        let rows_per_page = Schema::default().rows_per_page();
+        let mut table = TableOptions::default().open(&path).unwrap();
+        generate_rows(&mut table, 3 * rows_per_page).unwrap();
+        table.close().unwrap();
+        drop(table);
+
+        // Flushing page 0 leaves the cursor at the start of page 1; reading
+        // page 2 must not pick up from there.
+        let mut table = TableOptions::default().open(&path).unwrap();
+        table.pager.get_page(0).unwrap();
+        table
+            .pager
+            .flush_page(0, Schema::DEFAULT_PAGE_SIZE)
+            .unwrap();
+
+        let first = table.deserialize_row(0).unwrap();
+        let last = table.deserialize_row(3 * rows_per_page - 1).unwrap();
+        assert_eq!(first.id, 1);
+        assert_eq!(last.id, 3 * rows_per_page as u32);
+    }
+
+    #[test]
+    fn test_where_not() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 2 bob b@b.com 30",
+            "insert 3 null c@b.com 40",
+            "select id where not id = 1",
+            "select id where not username = alice",
+            "select id where not not username = 'alice'",
+            "select id where not email like '%b.com'",
+            "select where not age = old",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (2)\n\
+             (3)\n\
+             mysqlite> (2)\n\
+             mysqlite> (1)\n\
+             mysqlite> mysqlite> Type mismatch.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_distinct() {
+        let scripts = [
+            "insert 1 alice a@b.com 20",
+            "insert 2 bob b@b.com 30",
+            "insert 3 alice c@b.com 20",
+            "insert 4 alice d@b.com 40",
+            "insert 5 bob b@b.com 30",
+            "select distinct username",
+            "select distinct username, age where id like 4",
+            "select distinct *",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (alice)\n\
+             (bob)\n\
+             mysqlite> (alice 40)\n\
+             mysqlite> (1 alice a@b.com 20)\n\
+             (2 bob b@b.com 30)\n\
+             (3 alice c@b.com 20)\n\
+             (4 alice d@b.com 40)\n\
+             (5 bob b@b.com 30)\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_echo() {
+        let scripts = [
+            "insert 1 a a@b.com 20",
+            ".echo on",
+            "insert 2 b b@b.com 30",
+            ".echo off",
+            "select",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> mysqlite> db > insert 2 b b@b.com 30\n\
+             Executed. (1 row)\n\
+             mysqlite> mysqlite> (1 a a@b.com 20)\n\
+             (2 b b@b.com 30)\n\
+             mysqlite> "
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.db");
+        let mut table = Table::with_options(&path, TableOptions::default().mode(0o644)).unwrap();
+        table.close().unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_select_sum_avg() {
+        let scripts = [
+            "select sum(id)",
+            "select avg(id)",
+            "insert 1 alice a@b.com 30",
+            "insert 2 bob b@b.com",
+            "insert 4 alan c@d.com 41",
+            "select sum(id)",
+            "select avg(id)",
+            "select avg(age)",
+            "select sum(id) where username like 'a%'",
+            "select sum(username)",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
+        let output = run_scripts(&scripts, &path).unwrap();
+
+        assert_eq!(
+            output,
+            "mysqlite> sum(id): NULL\n\
+             mysqlite> avg(id): NULL\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> sum(id): 7\n\
+             mysqlite> avg(id): 2.3333333333333335\n\
+             mysqlite> avg(age): 35.5\n\
+             mysqlite> sum(id): 5\n\
+             mysqlite> Type mismatch.\n\
+             mysqlite> "
+        );
+    }
+
+    #[test]
+    fn test_select_group_by() {
+        let scripts = [
+            "insert 1 bob b@b.com 30",
+            "insert 2 alice a@b.com 20",
+            "insert 3 bob c@b.com 40",
+            "insert 4 alice d@b.com",
+            "insert 5 bob e@b.com 35",
+            "select username, count(*) group by username",
+            "select username, count(age), max(age), sum(id) group by username",
+            "select count(*), username where id like 4 group by username",
+            "select email, count(*) group by username",
+            ".exit",
+        ];
+        let (_dir, path) = create_test_db_file();
         let output = run_scripts(&scripts, &path).unwrap();
+
         assert_eq!(
             output,
-            "mysqlite> (1 user1 person1@example.com)\nmysqlite> "
+            "mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> Executed. (1 row)\n\
+             mysqlite> (alice 2)\n\
+             (bob 3)\n\
+             mysqlite> (alice 1 20 6)\n\
+             (bob 3 40 9)\n\
+             mysqlite> (1 alice)\n\
+             mysqlite> Syntax error. Could not parse statement.\n\
+             mysqlite> "
         );
     }
 
     fn run_scripts(commands: &[&str], path: &impl AsRef<Path>) -> Result<String, Box<dyn Error>> {
-        let input = String::from(commands.join("\n"));
+        let input = commands.join("\n");
         let mut input = io::Cursor::new(&input[..]);
         let mut output = vec![];
 
-        run(&mut input, &mut output, path)?;
+        run(
+            &mut input,
+            &mut output,
+            TableOptions::default().open(path)?,
+            true,
+            None,
+        )?;
 
         Ok(std::str::from_utf8(&output)?.into())
     }
@@ -476,6 +7400,6 @@ mod tests {
     fn create_test_db_file() -> (TempDir, PathBuf) {
         let dir = TempDir::new().unwrap();
         let path = dir.path().join("test.db");
-        return (dir, path);
+        (dir, path)
     }
 }